@@ -0,0 +1,677 @@
+use std::{fs, io::Read, path::Path};
+
+use flate2::read::ZlibDecoder;
+
+use crate::basic_types::*;
+use crate::image_settings::*;
+use crate::output_processor;
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+const HEADER_SIZE: usize = 128;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_OLD_PALETTE_6BIT: u16 = 0x0011;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+
+const CEL_RAW: u16 = 0;
+const CEL_LINKED: u16 = 1;
+const CEL_COMPRESSED: u16 = 2;
+
+const LAYER_VISIBLE: u16 = 1;
+const LAYER_GROUP: u16 = 1;
+/// Set in the header if the opacity of the layers should be used
+const HEADER_LAYER_OPACITY: u32 = 1;
+
+/// Everything impr uses from an Aseprite file
+pub struct Aseprite {
+    /// Every frame with its layers flattened, stacked on top of each other
+    pub animation: Animation,
+    /// The palette and flattened indices, for indexed files
+    pub indexed: Option<IndexedData>,
+    /// The frames of every tag, in the order they are played, as "anim_<tag>" sections
+    pub tags: Vec<Section>,
+}
+
+struct Layer {
+    name: String,
+    /// The names of the groups the layer is in
+    groups: Vec<String>,
+    /// Hidden layers, layers inside hidden groups, and group layers themselves are not drawn
+    visible: bool,
+    opacity: u8,
+}
+
+#[derive(Clone)]
+struct Cel {
+    layer: usize,
+    position: Vector2<i32>,
+    opacity: u8,
+    z_index: i16,
+    size: Vector2<usize>,
+    /// The pixels, in the color depth of the file
+    pixels: Vec<u8>,
+}
+
+/// Reads the little endian values Aseprite files are made of
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> &'a [u8] {
+        if self.position + length > self.data.len() {
+            panic!("Error: the Aseprite file ends too early!");
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+
+        bytes
+    }
+
+    fn skip(&mut self, length: usize) {
+        self.bytes(length);
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn word(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn short(&mut self) -> i16 {
+        i16::from_le_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn dword(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    fn string(&mut self) -> String {
+        let length = self.word() as usize;
+
+        String::from_utf8_lossy(self.bytes(length)).to_string()
+    }
+}
+
+/// Read an Aseprite file, flattening the layers of every frame. Returns None if the file isn't an .ase or .aseprite file
+pub fn read_aseprite(file: &str, settings: &TileSettings) -> Option<Aseprite> {
+    let extension = Path::new(file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    if !matches!(extension.as_deref(), Some("ase") | Some("aseprite")) {
+        return None;
+    }
+
+    let bytes = fs::read(file)
+        .unwrap_or_else(|error| panic!("Unable to open file {}! Reason: {}", file, error));
+
+    Some(parse_aseprite(&bytes, file, settings))
+}
+
+/// Read the frames, palette and tags of the bytes of an Aseprite file
+fn parse_aseprite(bytes: &[u8], file: &str, settings: &TileSettings) -> Aseprite {
+    let mut reader = Reader {
+        data: bytes,
+        position: 0,
+    };
+
+    // The header
+    reader.skip(4);
+    if reader.word() != HEADER_MAGIC {
+        panic!("Error: {} is not an Aseprite file!", file);
+    }
+    let frame_count = reader.word() as usize;
+    let size = Vector2 {
+        x: reader.word() as usize,
+        y: reader.word() as usize,
+    };
+    let color_depth = reader.word();
+    let flags = reader.dword();
+    reader.skip(2 + 4 + 4);
+    let transparent_index = reader.byte();
+    reader.position = HEADER_SIZE;
+
+    if !matches!(color_depth, 8 | 16 | 32) {
+        panic!(
+            "Error: {} has an unknown color depth of {}!",
+            file, color_depth
+        );
+    }
+
+    let mut layers: Vec<Layer> = Vec::new();
+    // The name of the group at each level, and whether it is visible, to hide the layers inside hidden groups
+    let mut groups: Vec<(String, bool)> = Vec::new();
+    let mut palette: Vec<Color> = Vec::new();
+    // Older files only have the palette in the old palette chunks
+    let mut old_palette: Vec<Color> = Vec::new();
+    let mut tags: Vec<Section> = Vec::new();
+    let mut frames: Vec<Vec<Cel>> = Vec::new();
+    let mut durations: Vec<f64> = Vec::new();
+
+    for _ in 0..frame_count {
+        let frame_start = reader.position;
+        let frame_length = reader.dword() as usize;
+
+        if reader.word() != FRAME_MAGIC {
+            panic!("Error: {} has a broken frame!", file);
+        }
+
+        let old_chunk_count = reader.word() as usize;
+        durations.push(reader.word() as f64);
+        reader.skip(2);
+        let chunk_count = match reader.dword() as usize {
+            0 => old_chunk_count,
+            chunk_count => chunk_count,
+        };
+
+        let mut cels: Vec<Cel> = Vec::new();
+
+        for _ in 0..chunk_count {
+            let chunk_start = reader.position;
+            let chunk_length = reader.dword() as usize;
+            let chunk_type = reader.word();
+
+            match chunk_type {
+                CHUNK_LAYER => {
+                    let layer_flags = reader.word();
+                    let layer_type = reader.word();
+                    let level = reader.word() as usize;
+                    reader.skip(2 + 2 + 2);
+                    let opacity = reader.byte();
+                    reader.skip(3);
+                    let name = reader.string();
+
+                    groups.truncate(level);
+                    let visible = layer_flags & LAYER_VISIBLE != 0
+                        && groups.iter().all(|&(_, visible)| visible);
+                    let layer_groups = groups.iter().map(|(name, _)| name.clone()).collect();
+
+                    if layer_type == LAYER_GROUP {
+                        groups.push((name.clone(), visible));
+                    }
+
+                    layers.push(Layer {
+                        name,
+                        groups: layer_groups,
+                        visible: visible && layer_type != LAYER_GROUP,
+                        opacity: if flags & HEADER_LAYER_OPACITY != 0 {
+                            opacity
+                        } else {
+                            255
+                        },
+                    });
+                }
+                CHUNK_CEL => {
+                    let layer = reader.word() as usize;
+                    let position = Vector2 {
+                        x: reader.short() as i32,
+                        y: reader.short() as i32,
+                    };
+                    let opacity = reader.byte();
+                    let cel_type = reader.word();
+                    let z_index = reader.short();
+                    reader.skip(5);
+
+                    match cel_type {
+                        CEL_RAW | CEL_COMPRESSED => {
+                            let size = Vector2 {
+                                x: reader.word() as usize,
+                                y: reader.word() as usize,
+                            };
+                            let data = reader.bytes(chunk_start + chunk_length - reader.position);
+
+                            let pixels = if cel_type == CEL_RAW {
+                                data.to_vec()
+                            } else {
+                                let mut pixels: Vec<u8> = Vec::new();
+
+                                ZlibDecoder::new(data)
+                                    .read_to_end(&mut pixels)
+                                    .unwrap_or_else(|error| {
+                                        panic!("Unable to read file {}! Reason: {}", file, error)
+                                    });
+
+                                pixels
+                            };
+
+                            if pixels.len() < size.x * size.y * color_depth as usize / 8 {
+                                panic!("Error: {} has a cel with missing pixels!", file);
+                            }
+
+                            cels.push(Cel {
+                                layer,
+                                position,
+                                opacity,
+                                z_index,
+                                size,
+                                pixels,
+                            });
+                        }
+                        CEL_LINKED => {
+                            // The same cel as the one in an earlier frame
+                            let linked_frame = reader.word() as usize;
+
+                            if let Some(cel) = frames
+                                .get(linked_frame)
+                                .and_then(|cels| cels.iter().find(|cel| cel.layer == layer))
+                            {
+                                cels.push(Cel {
+                                    position,
+                                    opacity,
+                                    z_index,
+                                    ..cel.clone()
+                                });
+                            }
+                        }
+                        // Tilemaps aren't supported
+                        _ => {}
+                    }
+                }
+                CHUNK_PALETTE => {
+                    let length = reader.dword() as usize;
+                    let first = reader.dword() as usize;
+                    let last = reader.dword() as usize;
+                    reader.skip(8);
+
+                    palette.resize(
+                        length.max(last + 1).max(palette.len()),
+                        Color::from([0, 0, 0, 255]),
+                    );
+
+                    for entry in palette[first..=last].iter_mut() {
+                        let entry_flags = reader.word();
+                        *entry = Color::from([
+                            reader.byte(),
+                            reader.byte(),
+                            reader.byte(),
+                            reader.byte(),
+                        ]);
+
+                        if entry_flags & 1 != 0 {
+                            reader.string();
+                        }
+                    }
+                }
+                CHUNK_OLD_PALETTE | CHUNK_OLD_PALETTE_6BIT => {
+                    let packet_count = reader.word();
+                    let mut index = 0;
+
+                    for _ in 0..packet_count {
+                        index += reader.byte() as usize;
+                        let color_count = match reader.byte() {
+                            0 => 256,
+                            color_count => color_count as usize,
+                        };
+
+                        old_palette.resize(
+                            old_palette.len().max(index + color_count),
+                            Color::from([0, 0, 0, 255]),
+                        );
+
+                        for entry in old_palette[index..index + color_count].iter_mut() {
+                            let [r, g, b] = [reader.byte(), reader.byte(), reader.byte()];
+
+                            *entry = if chunk_type == CHUNK_OLD_PALETTE_6BIT {
+                                // Scale 0-63 up to 0-255
+                                Color::from([
+                                    r << 2 | r >> 4,
+                                    g << 2 | g >> 4,
+                                    b << 2 | b >> 4,
+                                    255,
+                                ])
+                            } else {
+                                Color::from([r, g, b, 255])
+                            };
+                        }
+
+                        index += color_count;
+                    }
+                }
+                CHUNK_TAGS => {
+                    let tag_count = reader.word();
+                    reader.skip(8);
+
+                    for _ in 0..tag_count {
+                        let from = reader.word();
+                        let to = reader.word();
+                        let direction = reader.byte();
+                        reader.skip(2 + 6 + 3 + 1);
+                        let name = reader.string();
+
+                        tags.push(Section {
+                            name: format!("anim_{}", output_processor::get_file_safe_name(&name)),
+                            data: get_tag_frames(from, to, direction)
+                                .iter()
+                                .flat_map(|frame| frame.to_le_bytes())
+                                .collect(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            reader.position = chunk_start + chunk_length;
+        }
+
+        frames.push(cels);
+        reader.position = frame_start + frame_length;
+    }
+
+    if palette.is_empty() {
+        palette = old_palette;
+    }
+
+    // Only flatten the chosen layers, where choosing a group chooses every layer inside it
+    for layer in layers.iter_mut() {
+        let is_in = |names: &[String]| {
+            names.contains(&layer.name) || layer.groups.iter().any(|group| names.contains(group))
+        };
+
+        if !settings.layers.is_empty() {
+            layer.visible = is_in(&settings.layers);
+        }
+
+        if is_in(&settings.skip_layers) {
+            layer.visible = false;
+        }
+    }
+
+    let mut data: Vec<Color> = Vec::new();
+    let mut indices: Vec<u8> = Vec::new();
+
+    for mut cels in frames {
+        // Layers are drawn from the bottom up, and the z-index moves a cel up or down
+        cels.retain(|cel| layers.get(cel.layer).is_some_and(|layer| layer.visible));
+        cels.sort_by_key(|cel| (cel.layer as i32 + cel.z_index as i32, cel.z_index));
+
+        let mut canvas = vec![Color::from([0, 0, 0, 0]); size.x * size.y];
+        let mut index_canvas = vec![transparent_index; size.x * size.y];
+
+        for cel in &cels {
+            let opacity = cel.opacity as u32 * layers[cel.layer].opacity as u32 / 255;
+
+            for y in 0..cel.size.y {
+                for x in 0..cel.size.x {
+                    let canvas_x = cel.position.x + x as i32;
+                    let canvas_y = cel.position.y + y as i32;
+
+                    if canvas_x < 0
+                        || canvas_y < 0
+                        || canvas_x as usize >= size.x
+                        || canvas_y as usize >= size.y
+                    {
+                        continue;
+                    }
+
+                    let i = canvas_y as usize * size.x + canvas_x as usize;
+                    let pixel = y * cel.size.x + x;
+
+                    match color_depth {
+                        8 => {
+                            // Indexed pixels can't be blended, so they replace what is below them
+                            let index = cel.pixels[pixel];
+
+                            if index != transparent_index {
+                                index_canvas[i] = index;
+                            }
+                        }
+                        16 => {
+                            let [value, alpha] = [cel.pixels[pixel * 2], cel.pixels[pixel * 2 + 1]];
+                            canvas[i] = blend(
+                                canvas[i],
+                                Color::from([value, value, value, alpha]),
+                                opacity,
+                            );
+                        }
+                        _ => {
+                            let color: [u8; 4] =
+                                cel.pixels[pixel * 4..pixel * 4 + 4].try_into().unwrap();
+                            canvas[i] = blend(canvas[i], Color::from(color), opacity);
+                        }
+                    }
+                }
+            }
+        }
+
+        if color_depth == 8 {
+            canvas = index_canvas
+                .iter()
+                .map(|&index| get_palette_color(&palette, index, transparent_index))
+                .collect();
+            indices.append(&mut index_canvas);
+        }
+
+        data.append(&mut canvas);
+    }
+
+    let indexed = (color_depth == 8).then(|| IndexedData {
+        indices,
+        palette: (0..palette.len())
+            .map(|index| get_palette_color(&palette, index as u8, transparent_index))
+            .collect(),
+    });
+
+    Aseprite {
+        animation: Animation {
+            data,
+            frame_size: size,
+            durations,
+        },
+        indexed,
+        tags,
+    }
+}
+
+/// The color of a palette index, where the transparent index is fully transparent
+fn get_palette_color(palette: &[Color], index: u8, transparent_index: u8) -> Color {
+    let color = palette
+        .get(index as usize)
+        .copied()
+        .unwrap_or(Color::from([0, 0, 0, 255]));
+
+    if index == transparent_index {
+        Color { a: 0, ..color }
+    } else {
+        color
+    }
+}
+
+/// Draw a color over another, with the given extra opacity (0-255)
+fn blend(below: Color, above: Color, opacity: u32) -> Color {
+    let above_alpha = above.a as u32 * opacity / 255;
+    let below_alpha = below.a as u32 * (255 - above_alpha) / 255;
+    let alpha = above_alpha + below_alpha;
+
+    if alpha == 0 {
+        return Color::from([0, 0, 0, 0]);
+    }
+
+    let channel = |above: u8, below: u8| {
+        ((above as u32 * above_alpha + below as u32 * below_alpha) / alpha) as u8
+    };
+
+    Color {
+        r: channel(above.r, below.r),
+        g: channel(above.g, below.g),
+        b: channel(above.b, below.b),
+        a: alpha as u8,
+    }
+}
+
+/// The frames of a tag in the order they are played (forward, reverse, ping-pong, or ping-pong starting in reverse)
+fn get_tag_frames(from: u16, to: u16, direction: u8) -> Vec<u16> {
+    let forward: Vec<u16> = (from..=to).collect();
+    let reverse: Vec<u16> = (from..=to).rev().collect();
+
+    // The ends of a ping-pong aren't repeated
+    let inner = |frames: &[u16]| frames[1..frames.len().saturating_sub(1).max(1)].to_vec();
+
+    match direction {
+        1 => reverse,
+        2 => [forward.clone(), inner(&reverse)].concat(),
+        3 => [reverse.clone(), inner(&forward)].concat(),
+        _ => forward,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds Aseprite files out of chunks, for one frame
+    struct Builder {
+        color_depth: u16,
+        size: Vector2<u16>,
+        chunks: Vec<(u16, Vec<u8>)>,
+    }
+
+    impl Builder {
+        fn layer(mut self, name: &str, layer_type: u16, level: u16) -> Self {
+            let mut data: Vec<u8> = Vec::new();
+            data.extend(LAYER_VISIBLE.to_le_bytes());
+            data.extend(layer_type.to_le_bytes());
+            data.extend(level.to_le_bytes());
+            data.extend([0; 6]);
+            data.extend([255, 0, 0, 0]);
+            data.extend((name.len() as u16).to_le_bytes());
+            data.extend(name.as_bytes());
+
+            self.chunks.push((CHUNK_LAYER, data));
+            self
+        }
+
+        fn cel(mut self, layer: u16, size: Vector2<u16>, pixels: &[u8]) -> Self {
+            let mut data: Vec<u8> = Vec::new();
+            data.extend(layer.to_le_bytes());
+            data.extend([0; 4]);
+            data.push(255);
+            data.extend(CEL_RAW.to_le_bytes());
+            data.extend([0; 7]);
+            data.extend(size.x.to_le_bytes());
+            data.extend(size.y.to_le_bytes());
+            data.extend(pixels);
+
+            self.chunks.push((CHUNK_CEL, data));
+            self
+        }
+
+        fn chunk(mut self, chunk_type: u16, data: Vec<u8>) -> Self {
+            self.chunks.push((chunk_type, data));
+            self
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let mut frame: Vec<u8> = Vec::new();
+
+            for (chunk_type, data) in &self.chunks {
+                frame.extend((data.len() as u32 + 6).to_le_bytes());
+                frame.extend(chunk_type.to_le_bytes());
+                frame.extend(data);
+            }
+
+            let mut bytes: Vec<u8> = vec![0; 4];
+            bytes.extend(HEADER_MAGIC.to_le_bytes());
+            bytes.extend(1u16.to_le_bytes());
+            bytes.extend(self.size.x.to_le_bytes());
+            bytes.extend(self.size.y.to_le_bytes());
+            bytes.extend(self.color_depth.to_le_bytes());
+            bytes.extend(HEADER_LAYER_OPACITY.to_le_bytes());
+            bytes.resize(HEADER_SIZE, 0);
+
+            bytes.extend((frame.len() as u32 + 16).to_le_bytes());
+            bytes.extend(FRAME_MAGIC.to_le_bytes());
+            bytes.extend((self.chunks.len() as u16).to_le_bytes());
+            bytes.extend(100u16.to_le_bytes());
+            bytes.extend([0; 2]);
+            bytes.extend((self.chunks.len() as u32).to_le_bytes());
+            bytes.extend(frame);
+
+            bytes
+        }
+    }
+
+    fn builder(color_depth: u16) -> Builder {
+        Builder {
+            color_depth,
+            size: Vector2::new(2, 1),
+            chunks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reads_rgba_cels() {
+        let bytes = builder(32)
+            .layer("Layer 1", 0, 0)
+            .cel(0, Vector2::new(2, 1), &[255, 0, 0, 255, 0, 0, 255, 255])
+            .build();
+        let aseprite = parse_aseprite(&bytes, "test.aseprite", &TileSettings::default());
+
+        assert_eq!(
+            aseprite.animation.data,
+            vec![Color::from([255, 0, 0, 255]), Color::from([0, 0, 255, 255])]
+        );
+        assert_eq!(aseprite.animation.durations, vec![100.0]);
+        assert!(aseprite.indexed.is_none());
+    }
+
+    #[test]
+    fn reads_old_palette_chunks() {
+        // One packet of two colors starting at index 1, with 6 bit channels
+        let bytes = builder(8)
+            .chunk(CHUNK_OLD_PALETTE_6BIT, vec![1, 0, 1, 2, 63, 0, 0, 0, 63, 0])
+            .layer("Layer 1", 0, 0)
+            .cel(0, Vector2::new(2, 1), &[1, 2])
+            .build();
+        let aseprite = parse_aseprite(&bytes, "test.aseprite", &TileSettings::default());
+        let indexed = aseprite.indexed.unwrap();
+
+        assert_eq!(indexed.indices, vec![1, 2]);
+        assert_eq!(
+            indexed.palette,
+            vec![
+                Color::from([0, 0, 0, 0]),
+                Color::from([255, 0, 0, 255]),
+                Color::from([0, 255, 0, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn chooses_the_layers_inside_a_group() {
+        let bytes = builder(8)
+            .chunk(
+                CHUNK_OLD_PALETTE,
+                vec![1, 0, 0, 3, 0, 0, 0, 1, 1, 1, 2, 2, 2],
+            )
+            .layer("background", 0, 0)
+            .layer("body", LAYER_GROUP, 0)
+            .layer("arm", 0, 1)
+            .cel(0, Vector2::new(2, 1), &[1, 1])
+            .cel(2, Vector2::new(1, 1), &[2])
+            .build();
+        let settings = TileSettings {
+            layers: vec!["body".to_string()],
+            ..Default::default()
+        };
+        let aseprite = parse_aseprite(&bytes, "test.aseprite", &settings);
+
+        assert_eq!(aseprite.indexed.unwrap().indices, vec![2, 0]);
+    }
+
+    #[test]
+    fn plays_tags_in_order() {
+        assert_eq!(get_tag_frames(1, 3, 0), vec![1, 2, 3]);
+        assert_eq!(get_tag_frames(1, 3, 1), vec![3, 2, 1]);
+        assert_eq!(get_tag_frames(1, 3, 2), vec![1, 2, 3, 2]);
+        assert_eq!(get_tag_frames(1, 3, 3), vec![3, 2, 1, 2]);
+    }
+}
@@ -0,0 +1,268 @@
+use itertools::Itertools;
+
+use crate::basic_types::*;
+use crate::image_processor;
+use crate::image_settings::*;
+use crate::sprite_processor;
+
+/// Turn an attribute image (where every color stands for a value, like a kind of collision) into one value per tile,
+/// in the same order as the tile map
+pub fn create_attribute_map(
+    data: &[Color],
+    size: Vector2<usize>,
+    settings: &TileSettings,
+) -> Vec<u8> {
+    let values: Vec<u8> = data
+        .iter()
+        .map(|color| get_attribute_value(color, settings))
+        .collect();
+
+    get_tile_attributes(&values, size, settings, None)
+}
+
+/// Turn an attribute image into one value per tile, in the same order as the tiles of the image without a map. The
+/// frames, metatiles and sprites are arranged the same way as the tiles, and the frame index table (if the frames were
+/// deduplicated) tells which frames were kept
+pub fn create_frame_attribute_map(
+    data: &[Color],
+    size: Vector2<usize>,
+    settings: &TileSettings,
+    frame_index_table: Option<&[u8]>,
+) -> Vec<u8> {
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+    let frame_size = settings.frame_size.unwrap_or(size);
+    let frame_tiles = Vector2 {
+        x: frame_size.x / tile_size.x,
+        y: frame_size.y / tile_size.y,
+    };
+
+    let values: Vec<u8> = data
+        .iter()
+        .map(|color| get_attribute_value(color, settings))
+        .collect();
+    let frames = image_processor::split_frames(
+        &values,
+        &TileSettings {
+            image_size: size,
+            ..settings.clone()
+        },
+    );
+
+    // A frame was kept if it is the first one to use its unique frame
+    let mut kept_frames = 0;
+    let mut is_kept = |frame: usize| match frame_index_table {
+        Some(table) => {
+            let entry = u16::from_le_bytes([table[frame * 2], table[frame * 2 + 1]]) as usize;
+            let kept = entry == kept_frames;
+
+            kept_frames += kept as usize;
+            kept
+        }
+        None => true,
+    };
+
+    let frames: Vec<Vec<u8>> = frames
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| is_kept(i))
+        .map(|(i, frame)| {
+            let attributes = get_tile_attributes(frame, frame_size, settings, Some(i));
+
+            // Metatiles have their tiles grouped together
+            image_processor::tile(&attributes, frame_tiles, settings.metatile_size).concat()
+        })
+        .collect();
+
+    match settings.sprite_mapping {
+        Some(SpriteMapping::TwoD) => {
+            // Place a tile filled with its value for every tile, so the values end up where the tiles of the sprites are
+            let tile_length = tile_size.x * tile_size.y * settings.bpp.to_num() / 8;
+            let frames: Vec<Vec<u8>> = frames
+                .iter()
+                .map(|frame| {
+                    frame
+                        .iter()
+                        .flat_map(|&value| vec![value; tile_length])
+                        .collect()
+                })
+                .collect();
+
+            let (charblock, _) = sprite_processor::place_sprites_2d(&frames, settings);
+
+            charblock.into_iter().step_by(tile_length).collect()
+        }
+        _ => frames.concat(),
+    }
+}
+
+/// Choose the value of every tile of an image (or one of its frames) from the values of its pixels, going left to right,
+/// then top to bottom
+fn get_tile_attributes(
+    values: &Vec<u8>,
+    size: Vector2<usize>,
+    settings: &TileSettings,
+    frame: Option<usize>,
+) -> Vec<u8> {
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+    let tiles_per_row = size.x / tile_size.x;
+
+    image_processor::tile(values, size, tile_size)
+        .iter()
+        .enumerate()
+        .map(|(i, values)| match settings.attribute_rule {
+            AttributeRule::Majority => {
+                // Ties go to the lowest value
+                let counts = values.iter().counts();
+
+                values
+                    .iter()
+                    .copied()
+                    .max_by_key(|value| (counts[value], std::cmp::Reverse(*value)))
+                    .unwrap_or(0)
+            }
+            AttributeRule::Strict => {
+                if !values.iter().all_equal() {
+                    panic!(
+                        "Error: the tile at ({}, {}){} of the attribute image has more than one attribute!",
+                        (i % tiles_per_row) * tile_size.x,
+                        (i / tiles_per_row) * tile_size.y,
+                        frame.map_or(String::new(), |frame| format!(" of frame {}", frame))
+                    );
+                }
+
+                values[0]
+            }
+        })
+        .collect()
+}
+
+/// The value of a color of the attribute image. Fully transparent pixels are 0
+fn get_attribute_value(color: &Color, settings: &TileSettings) -> u8 {
+    if color.a == 0 {
+        return 0;
+    }
+
+    match settings
+        .attribute_colors
+        .iter()
+        .find(|(entry, _)| (entry.r, entry.g, entry.b) == (color.r, color.g, color.b))
+    {
+        Some(&(_, value)) => value,
+        None => panic!(
+            "Error: the color #{:02X}{:02X}{:02X} of the attribute image isn't in --attribute-colors!",
+            color.r, color.g, color.b
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(value: u8) -> Color {
+        Color {
+            r: value * 10,
+            g: 0,
+            b: 0,
+            a: 255,
+        }
+    }
+
+    /// Make an attribute image where every 8x8 tile is filled with the color of one value, and the settings to read it
+    fn build_image(tiles: &[&[u8]]) -> (Vec<Color>, Vector2<usize>, TileSettings) {
+        let size = Vector2::new(tiles[0].len() * 8, tiles.len() * 8);
+        let data = (0..size.x * size.y)
+            .map(|i| color(tiles[i / size.x / 8][(i % size.x) / 8]))
+            .collect();
+
+        let settings = TileSettings {
+            bpp: BitsPerPixel::Bpp4,
+            image_size: size,
+            attribute_colors: (0..8).map(|value| (color(value), value)).collect(),
+            ..Default::default()
+        };
+
+        (data, size, settings)
+    }
+
+    #[test]
+    fn orders_attributes_like_metatiles() {
+        let (data, size, settings) = build_image(&[&[1, 2], &[3, 4]]);
+
+        assert_eq!(
+            create_attribute_map(&data, size, &settings),
+            vec![1, 2, 3, 4]
+        );
+
+        // Each 8x16 metatile has its tiles together
+        let settings = TileSettings {
+            metatile_size: Vector2::new(1, 2),
+            ..settings
+        };
+
+        assert_eq!(
+            create_frame_attribute_map(&data, size, &settings, None),
+            vec![1, 3, 2, 4]
+        );
+    }
+
+    #[test]
+    fn leaves_out_deduplicated_frames() {
+        let (data, size, settings) = build_image(&[&[1], &[1], &[2], &[1]]);
+        let settings = TileSettings {
+            frame_size: Some(Vector2::new(8, 8)),
+            ..settings
+        };
+
+        // The second and last frames are copies of the first one, the third one is flipped
+        let table: Vec<u8> = [0u16, 0, 1, 0x8000]
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect();
+
+        assert_eq!(
+            create_frame_attribute_map(&data, size, &settings, Some(&table)),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn places_attributes_like_2d_sprites() {
+        let (data, size, settings) = build_image(&[&[1], &[2], &[3], &[4]]);
+        let settings = TileSettings {
+            frame_size: Some(Vector2::new(8, 16)),
+            sprite_mapping: Some(SpriteMapping::TwoD),
+            ..settings
+        };
+
+        // The second row of tiles of each sprite is a row of the character block further
+        let map = create_frame_attribute_map(&data, size, &settings, None);
+
+        assert_eq!(map.len(), 64);
+        assert_eq!((map[0], map[1], map[32], map[33]), (1, 3, 2, 4));
+        assert!(map
+            .iter()
+            .enumerate()
+            .all(|(i, &value)| [0, 1, 32, 33].contains(&i) || value == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "the tile at (0, 0) of frame 1 of the attribute image")]
+    fn rejects_mixed_tiles_with_the_strict_rule() {
+        let (mut data, size, settings) = build_image(&[&[1], &[2]]);
+        let settings = TileSettings {
+            frame_size: Some(Vector2::new(8, 8)),
+            attribute_rule: AttributeRule::Strict,
+            ..settings
+        };
+        data[64] = color(3);
+
+        create_frame_attribute_map(&data, size, &settings, None);
+    }
+}
@@ -67,10 +67,36 @@ where
 
 pub type Color = ColorStruct<u8>;
 
+/// The palette of an indexed image, and the palette index of every pixel, exactly as they are stored in the file
+#[derive(Clone)]
+pub struct IndexedData {
+    pub indices: Vec<u8>,
+    pub palette: Vec<Color>,
+}
+
+/// Every frame of an animated image, stacked on top of each other, and how long each frame is shown in milliseconds
+pub struct Animation {
+    pub data: Vec<Color>,
+    pub frame_size: Vector2<usize>,
+    pub durations: Vec<f64>,
+}
+
 /// Raw data of the image, in vectors of bytes, ready to be written to a file
+#[derive(Default)]
 pub struct FinishedRawData {
     pub image_data: Vec<u8>,
     pub palette_data: Vec<u8>,
+    /// The colors of the palette, in the order they were written to palette_data
+    pub palette: Vec<Color>,
+    pub map_data: Vec<u8>,
+    /// Any other data, which each get written to their own file
+    pub sections: Vec<Section>,
+}
+
+/// A named block of data, which gets written to its own file
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
 }
 
 pub fn error(error_string: &str) {
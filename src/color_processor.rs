@@ -1,23 +1,260 @@
-const BIT16_TO_BIT5_CONVERSION_FACTOR: f32 = 31.0 / 255.0;
+use crate::basic_types::Color;
+use crate::image_settings::{ChannelConversion, ColorEncoder, ColorEncoding, Endianness};
 
-pub fn bit16_to_bit5(num: u16) -> u16 {
-    ((num as f32) * BIT16_TO_BIT5_CONVERSION_FACTOR) as u16
+/// Reduce an 8 bit channel to the given number of bits
+pub fn bit8_to_bits(num: u16, bits: u32, conversion: ChannelConversion) -> u16 {
+    let max = (1 << bits) - 1;
+
+    match conversion {
+        ChannelConversion::Truncate => num * max / 255,
+        ChannelConversion::Round => (num * max + 127) / 255,
+        ChannelConversion::Shift => num >> (8 - bits),
+    }
+}
+
+//xbbbbbgggggrrrrr
+pub fn create_16bit_color(r: u16, g: u16, b: u16, _a: u16, conversion: ChannelConversion) -> u16 {
+    (bit8_to_bits(r, 5, conversion))
+        | ((bit8_to_bits(g, 5, conversion)) << 5)
+        | ((bit8_to_bits(b, 5, conversion)) << 10)
 }
 
-//rrrrrgggggbbbbba
-pub fn create_16bit_color(r: u16, g: u16, b: u16, _a: u16) -> u16 {
-    (bit16_to_bit5(r)) | ((bit16_to_bit5(g)) << 5) | ((bit16_to_bit5(b)) << 10)
+//abbbbbgggggrrrrr
+// The alpha bit is set for pixels that are at least half opaque
+pub fn create_16bit_color_argb16(
+    r: u16,
+    g: u16,
+    b: u16,
+    a: u16,
+    conversion: ChannelConversion,
+) -> u16 {
+    bit8_to_bits(r, 5, conversion)
+        | (bit8_to_bits(g, 5, conversion) << 5)
+        | (bit8_to_bits(b, 5, conversion) << 10)
+        | ((if a >= 0x80 { 1 } else { 0 }) << 15)
+}
+
+//0000bbb0ggg0rrr0 (Mega Drive, big endian)
+pub fn create_9bit_color(r: u16, g: u16, b: u16, conversion: ChannelConversion) -> u16 {
+    (bit8_to_bits(r, 3, conversion) << 1)
+        | (bit8_to_bits(g, 3, conversion) << 5)
+        | (bit8_to_bits(b, 3, conversion) << 9)
+}
+
+//00bbggrr (Master System)
+pub fn create_6bit_color(r: u16, g: u16, b: u16, conversion: ChannelConversion) -> u8 {
+    (bit8_to_bits(r, 2, conversion)
+        | (bit8_to_bits(g, 2, conversion) << 2)
+        | (bit8_to_bits(b, 2, conversion) << 4)) as u8
+}
+
+//0000bbbbggggrrrr (Game Gear)
+pub fn create_12bit_color(r: u16, g: u16, b: u16, conversion: ChannelConversion) -> u16 {
+    bit8_to_bits(r, 4, conversion)
+        | (bit8_to_bits(g, 4, conversion) << 4)
+        | (bit8_to_bits(b, 4, conversion) << 8)
+}
+
+/// The colors of the NES's own palette
+const NES_PALETTE: [u32; 64] = [
+    0x7C7C7C, 0x0000FC, 0x0000BC, 0x4428BC, 0x940084, 0xA80020, 0xA81000, 0x881400, //
+    0x503000, 0x007800, 0x006800, 0x005800, 0x004058, 0x000000, 0x000000, 0x000000, //
+    0xBCBCBC, 0x0078F8, 0x0058F8, 0x6844FC, 0xD800CC, 0xE40058, 0xF83800, 0xE45C10, //
+    0xAC7C00, 0x00B800, 0x00A800, 0x00A844, 0x008888, 0x000000, 0x000000, 0x000000, //
+    0xF8F8F8, 0x3CBCFC, 0x6888FC, 0x9878F8, 0xF878F8, 0xF85898, 0xF87858, 0xFCA044, //
+    0xF8B800, 0xB8F818, 0x58D854, 0x58F898, 0x00E8D8, 0x787878, 0x000000, 0x000000, //
+    0xFCFCFC, 0xA4E4FC, 0xB8B8F8, 0xD8B8F8, 0xF8B8F8, 0xF8A4C0, 0xF0D0B0, 0xFCE0A8, //
+    0xF8D878, 0xD8F878, 0xB8F8B8, 0xB8F8D8, 0x00FCFC, 0xF8D8F8, 0x000000, 0x000000, //
+];
+
+// Index of the closest color in the NES's palette
+pub fn create_nes_color(color: &Color) -> u8 {
+    (0..NES_PALETTE.len())
+        // Skip the duplicate blacks, and $0D, which can confuse TVs
+        .filter(|&i| i == 0x0F || (i & 0xF < 0xE && i != 0x0D && i != 0x1D))
+        .min_by_key(|&i| {
+            let [_, r, g, b] = NES_PALETTE[i].to_be_bytes();
+
+            (r as i32 - color.r as i32).pow(2)
+                + (g as i32 - color.g as i32).pow(2)
+                + (b as i32 - color.b as i32).pow(2)
+        })
+        .unwrap() as u8
+}
+
+// The Game Boy's shade of a color, from 0 (white) to 3 (black)
+pub fn create_gb_shade(color: &Color, conversion: ChannelConversion) -> u8 {
+    3 - bit8_to_bits((get_luminance(color) / 1000) as u16, 2, conversion) as u8
+}
+
+//xrrrrrgggggbbbbb
+pub fn create_16bit_color_rgb555(r: u16, g: u16, b: u16, conversion: ChannelConversion) -> u16 {
+    bit8_to_bits(b, 5, conversion)
+        | (bit8_to_bits(g, 5, conversion) << 5)
+        | (bit8_to_bits(r, 5, conversion) << 10)
+}
+
+//rrrrrggggggbbbbb
+pub fn create_16bit_color_rgb565(r: u16, g: u16, b: u16, conversion: ChannelConversion) -> u16 {
+    bit8_to_bits(b, 5, conversion)
+        | (bit8_to_bits(g, 6, conversion) << 5)
+        | (bit8_to_bits(r, 5, conversion) << 11)
 }
 
 //arrrrrgggggbbbbb
-pub fn create_16bit_color_argb16(r: u16, g: u16, b: u16, a: u16) -> u16 {
-    bit16_to_bit5(r)
-        | (bit16_to_bit5(g) << 5)
-        | (bit16_to_bit5(b) << 10)
-        | ((if a > 0 { 1 } else { 0 }) << 15)
+// The alpha bit is set for pixels that are at least half opaque
+pub fn create_16bit_color_argb1555(
+    r: u16,
+    g: u16,
+    b: u16,
+    a: u16,
+    conversion: ChannelConversion,
+) -> u16 {
+    create_16bit_color_rgb555(r, g, b, conversion) | ((if a >= 0x80 { 1 } else { 0 }) << 15)
+}
+
+//aaaarrrrggggbbbb
+pub fn create_16bit_color_argb4444(
+    r: u16,
+    g: u16,
+    b: u16,
+    a: u16,
+    conversion: ChannelConversion,
+) -> u16 {
+    bit8_to_bits(b, 4, conversion)
+        | (bit8_to_bits(g, 4, conversion) << 4)
+        | (bit8_to_bits(r, 4, conversion) << 8)
+        | (bit8_to_bits(a, 4, conversion) << 12)
+}
+
+//rrrrrrrrggggggggbbbbbbbb
+pub fn create_24bit_color(r: u32, g: u32, b: u32) -> u32 {
+    (b & 0xFF) | ((g & 0xFF) << 8) | ((r & 0xFF) << 16)
+}
+
+/// Convert a color to bytes with the given encoder. The endianness is only used for encodings that are more than one byte
+pub fn encode_color(color: &Color, encoder: &ColorEncoder) -> Vec<u8> {
+    let conversion = encoder.conversion;
+    let (r, g, b, a) = (
+        color.r as u16,
+        color.g as u16,
+        color.b as u16,
+        color.a as u16,
+    );
+
+    // The value of the color, and how many bytes it takes up
+    let (value, length): (u32, usize) = match encoder.encoding {
+        ColorEncoding::Bgr555 => (create_16bit_color(r, g, b, a, conversion) as u32, 2),
+        ColorEncoding::Rgb555 => (create_16bit_color_rgb555(r, g, b, conversion) as u32, 2),
+        ColorEncoding::Rgb565 => (create_16bit_color_rgb565(r, g, b, conversion) as u32, 2),
+        ColorEncoding::Abgr1555 => (create_16bit_color_argb16(r, g, b, a, conversion) as u32, 2),
+        ColorEncoding::Argb1555 => (
+            create_16bit_color_argb1555(r, g, b, a, conversion) as u32,
+            2,
+        ),
+        ColorEncoding::Argb4444 => (
+            create_16bit_color_argb4444(r, g, b, a, conversion) as u32,
+            2,
+        ),
+        ColorEncoding::Rgba8888 => (
+            create_32bit_color(r as u32, g as u32, b as u32, a as u32),
+            4,
+        ),
+        ColorEncoding::Bgr888 => (create_24bit_color(r as u32, g as u32, b as u32), 3),
+        ColorEncoding::MegaDrive => (create_9bit_color(r, g, b, conversion) as u32, 2),
+        ColorEncoding::MasterSystem => (create_6bit_color(r, g, b, conversion) as u32, 1),
+        ColorEncoding::GameGear => (create_12bit_color(r, g, b, conversion) as u32, 2),
+        ColorEncoding::Nes => (create_nes_color(color) as u32, 1),
+        ColorEncoding::GameBoy => (create_gb_shade(color, conversion) as u32, 1),
+    };
+
+    match encoder.endianness {
+        Endianness::Little => value.to_le_bytes()[..length].to_vec(),
+        Endianness::Big => value.to_be_bytes()[4 - length..].to_vec(),
+    }
+}
+
+/// Convert every color of a palette to bytes with the given encoder. Game Boy shades are packed four to a byte, with the
+/// first color in the lowest bits
+pub fn encode_palette(palette: &[Color], encoder: &ColorEncoder) -> Vec<u8> {
+    match encoder.encoding {
+        ColorEncoding::GameBoy => palette
+            .chunks(4)
+            .map(|colors| {
+                colors.iter().enumerate().fold(0u8, |byte, (i, color)| {
+                    byte | create_gb_shade(color, encoder.conversion) << (i * 2)
+                })
+            })
+            .collect(),
+        _ => palette
+            .iter()
+            .flat_map(|color| encode_color(color, encoder))
+            .collect(),
+    }
 }
 
 //rrrrrrrrggggggggbbbbbbbbaaaaaaaa
 pub fn create_32bit_color(r: u32, g: u32, b: u32, a: u32) -> u32 {
     ((r & 0xFF) << 24) | ((g & 0xFF) << 16) | ((b & 0xFF) << 8) | (a & 0xFF)
 }
+
+/// Perceived brightness of a color (ITU-R BT.601 weights), from 0 to 255000
+pub fn get_luminance(color: &Color) -> u32 {
+    299 * color.r as u32 + 587 * color.g as u32 + 114 * color.b as u32
+}
+
+/// Hue of a color in degrees (0-359), or None for grays, which don't have a hue
+pub fn get_hue(color: &Color) -> Option<u16> {
+    let (r, g, b) = (color.r as i32, color.g as i32, color.b as i32);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    if delta == 0 {
+        return None;
+    }
+
+    let hue = if max == r {
+        60 * (g - b) / delta
+    } else if max == g {
+        60 * (b - r) / delta + 120
+    } else {
+        60 * (r - g) / delta + 240
+    };
+
+    Some(hue.rem_euclid(360) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_channels() {
+        let convert = |num: u16, bits: u32| {
+            [
+                ChannelConversion::Truncate,
+                ChannelConversion::Round,
+                ChannelConversion::Shift,
+            ]
+            .map(|conversion| bit8_to_bits(num, bits, conversion))
+        };
+
+        // Truncate rounds mid-tones down, Round goes to the nearest value, Shift drops the lowest bits
+        assert_eq!(convert(0x84, 3), [3, 4, 4]);
+        assert_eq!(convert(0x84, 5), [16, 16, 16]);
+        assert_eq!(convert(0x07, 5), [0, 1, 0]);
+        assert_eq!(convert(0xFF, 5), [31, 31, 31]);
+        assert_eq!(convert(0xFF, 3), [7, 7, 7]);
+    }
+
+    #[test]
+    fn round_trips_5_bit_channels_with_round() {
+        for value in 0..32u16 {
+            // The usual expansion to 8 bits, which repeats the highest bits in the lowest ones
+            let expanded = value << 3 | value >> 2;
+
+            assert_eq!(bit8_to_bits(expanded, 5, ChannelConversion::Round), value);
+        }
+    }
+}
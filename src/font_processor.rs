@@ -0,0 +1,158 @@
+use std::fs;
+
+use crate::basic_types::*;
+use crate::image_processor;
+use crate::image_settings::*;
+
+/// Make the tables of a font, where every frame of the image is a glyph. The glyph table has the advance width of every
+/// glyph as one byte. The character map has an entry for every character, sorted by character code: the code as a 32 bit
+/// number, then the glyph it uses as a 16 bit number, then 16 bits of padding
+pub fn create_font_tables(data: &[Color], settings: &TileSettings) -> Vec<Section> {
+    let glyph_size = settings.frame_size.unwrap_or(settings.image_size);
+
+    // Find the background the same way the image does, falling back to the color of the top left corner for fonts drawn
+    // on an opaque background
+    let (data, settings) = image_processor::apply_transparency(data.to_vec(), settings.clone());
+    let background = settings.transparent_color.or(data.first().copied());
+    let glyphs = image_processor::split_frames(&data, &settings);
+
+    let widths: Vec<u8> = glyphs
+        .iter()
+        .enumerate()
+        .map(|(index, glyph)| {
+            // The rightmost column that has a pixel that isn't the background
+            let width = (0..glyph_size.x)
+                .rev()
+                .find(|&x| (0..glyph_size.y).any(|y| Some(glyph[y * glyph_size.x + x]) != background))
+                .map(|x| x + 1);
+
+            let width = match width {
+                Some(width) => width + settings.glyph_spacing as usize,
+                // Empty glyphs (like the space) are half as wide as the glyph
+                None => glyph_size.x / 2,
+            };
+
+            u8::try_from(width).unwrap_or_else(|_| {
+                panic!(
+                    "Error: glyph {} is {} pixels wide (with the glyph spacing), but glyphs can be at most 255 pixels wide!",
+                    index, width
+                )
+            })
+        })
+        .collect();
+
+    let characters: Vec<u32> = match settings.charset {
+        Some(ref charset) => fs::read_to_string(charset)
+            .unwrap_or_else(|error| panic!("Unable to open file {}! Reason: {}", charset, error))
+            .chars()
+            .filter(|&character| character != '\n' && character != '\r')
+            .map(u32::from)
+            .collect(),
+        None => {
+            (settings.first_character..settings.first_character + glyphs.len() as u32).collect()
+        }
+    };
+
+    if characters.len() > glyphs.len() {
+        panic!(
+            "Error: the character set has {} characters, but the font only has {} glyphs!",
+            characters.len(),
+            glyphs.len()
+        );
+    }
+
+    let mut character_map: Vec<(u32, u16)> = characters
+        .into_iter()
+        .enumerate()
+        .map(|(glyph, character)| (character, glyph as u16))
+        .collect();
+    character_map.sort_by_key(|&(character, _)| character);
+
+    vec![
+        Section {
+            name: "glyphs".to_string(),
+            data: widths,
+        },
+        Section {
+            name: "charmap".to_string(),
+            data: character_map
+                .iter()
+                .flat_map(|&(character, glyph)| {
+                    [
+                        character.to_le_bytes().as_slice(),
+                        glyph.to_le_bytes().as_slice(),
+                        &[0, 0],
+                    ]
+                    .concat()
+                })
+                .collect(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    const BLACK: Color = Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+
+    /// A font of 8x8 glyphs side by side on a white background, where each glyph has a black column at the given x
+    fn build_font(columns: &[Option<usize>], glyph_spacing: u8) -> (Vec<Color>, TileSettings) {
+        let size = Vector2::new(columns.len() * 8, 8);
+        let data = (0..size.x * size.y)
+            .map(|i| match columns[(i % size.x) / 8] {
+                Some(x) if i % 8 == x => BLACK,
+                _ => WHITE,
+            })
+            .collect();
+
+        let settings = TileSettings {
+            bpp: BitsPerPixel::Bpp1,
+            transparent_color: None,
+            image_size: size,
+            frame_size: Some(Vector2::new(8, 8)),
+            font: true,
+            glyph_spacing,
+            ..Default::default()
+        };
+
+        (data, settings)
+    }
+
+    #[test]
+    fn measures_glyphs_on_an_opaque_background() {
+        let (data, settings) = build_font(&[Some(1), Some(4), None], 1);
+
+        assert_eq!(create_font_tables(&data, &settings)[0].data, vec![3, 6, 4]);
+    }
+
+    #[test]
+    fn measures_glyphs_with_the_transparent_color() {
+        let (data, settings) = build_font(&[Some(2), None], 0);
+        let settings = TileSettings {
+            transparent_color: Some(WHITE),
+            ..settings
+        };
+
+        assert_eq!(create_font_tables(&data, &settings)[0].data, vec![3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "glyph 0 is 263 pixels wide")]
+    fn rejects_glyphs_wider_than_a_byte() {
+        let (data, settings) = build_font(&[Some(7)], 255);
+
+        create_font_tables(&data, &settings);
+    }
+}
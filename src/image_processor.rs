@@ -1,14 +1,26 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
 use crate::basic_types::*;
 use crate::color_processor;
-use crate::color_processor::create_16bit_color_argb16;
+use crate::font_processor;
 use crate::image_settings::*;
+use crate::map_processor;
+use crate::nes_processor;
+use crate::sprite_processor;
+use crate::texture_processor;
 use itertools::Itertools;
 
-pub fn process_image(data: Vec<Color>, settings: TileSettings) -> FinishedRawData {
+pub fn process_image(
+    data: Vec<Color>,
+    indexed: Option<IndexedData>,
+    settings: TileSettings,
+) -> FinishedRawData {
     let expected_length: usize =
         settings.size_per_tile.x as usize * settings.size_per_tile.y as usize;
 
-    if data.len() < expected_length {
+    // Bitmaps can be smaller than a tile
+    if settings.layout == Layout::Tiled && data.len() < expected_length {
         panic!(
             "File: {} Line: {}, data.len() < expected_length",
             file!(),
@@ -16,86 +28,444 @@ pub fn process_image(data: Vec<Color>, settings: TileSettings) -> FinishedRawDat
         );
     }
 
-    // Split the image into tiles
-    let tiled = tile(
-        &data,
-        settings.image_size,
-        Vector2 {
-            x: settings.size_per_tile.x as usize,
-            y: settings.size_per_tile.y as usize,
-        },
-    );
-
-    // Group metatiles together
-    let metatiled = tile_sequentially(
-        &tiled,
-        Vector2 {
-            x: settings.image_size.x / settings.size_per_tile.x as usize,
-            y: settings.image_size.y / settings.size_per_tile.y as usize,
-        },
-        Vector2 {
-            x: settings.metatile_size.x as usize,
-            y: settings.metatile_size.y as usize,
-        },
-    );
-
     // This is the raw byte arrays that will be written to the file
     let mut final_data: FinishedRawData = FinishedRawData {
         image_data: Vec::new(),
         palette_data: Vec::new(),
+        palette: Vec::new(),
+        map_data: Vec::new(),
+        sections: Vec::new(),
     };
 
+    // Textures are stored texel by texel instead of in tiles
+    if let Some(texture_format) = settings.texture_format {
+        texture_processor::process_texture(
+            data,
+            indexed,
+            texture_format,
+            &settings,
+            &mut final_data,
+        );
+
+        return final_data;
+    }
+
+    // Every frame of a font is a glyph
+    if settings.font {
+        final_data
+            .sections
+            .extend(font_processor::create_font_tables(&data, &settings));
+    }
+
     match settings.bpp {
-        BitsPerPixel::Bpp4 | BitsPerPixel::Bpp8 => {
-            // Get the palete of the entire image
-            let palette = get_image_palette(&data, &settings);
+        BitsPerPixel::Bpp1 | BitsPerPixel::Bpp2 | BitsPerPixel::Bpp4 | BitsPerPixel::Bpp8 => {
+            // Get the palette of the entire image, and the palette index of every pixel
+            let (mut palette, indices) = match indexed {
+                Some(indexed) => get_indexed_palette(indexed, &settings),
+                None if settings.tile_format == TileFormat::Nes => {
+                    let (data, settings) = apply_transparency(data, settings.clone());
+
+                    // Every area of the image gets its own palette, which go in the attribute table
+                    let (palette, indices, attribute_table) =
+                        nes_processor::get_nes_palette(&data, &settings);
+
+                    final_data.sections.push(Section {
+                        name: "attr".to_string(),
+                        data: attribute_table,
+                    });
+
+                    (palette, indices)
+                }
+                None => {
+                    let (data, settings) = apply_transparency(data, settings.clone());
 
-            // Process all the tiles and add it to final_data
-            for tile in metatiled {
-                let mut paletted = process_tile_paletted(&tile, &settings, &palette);
+                    let palette = get_image_palette(&data, &settings);
+                    let indices = convert_to_paletted(&data, &settings, &palette);
 
-                final_data.image_data.append(&mut paletted);
-            }
+                    (palette, indices)
+                }
+            };
+
+            pad_palette(&mut palette, &settings);
+
+            if settings.include_map_data {
+                if settings.metatile_size != Vector2::new(1, 1) {
+                    println!("Warning: metatiles are ignored when creating a map");
+                }
+                if settings.frame_size.is_some() {
+                    println!("Warning: frames are ignored when creating a map");
+                }
 
-            // Convert all the colors into palette into 16 bit color
-            for item in palette {
-                final_data.palette_data.append(
-                    &mut color_processor::create_16bit_color(
-                        item.r as u16,
-                        item.g as u16,
-                        item.b as u16,
-                        item.a as u16,
-                    )
-                    .to_le_bytes()
-                    .to_vec(),
+                // Split the image into tiles
+                let tiled = tile(
+                    &indices,
+                    settings.image_size,
+                    Vector2 {
+                        x: settings.size_per_tile.x as usize,
+                        y: settings.size_per_tile.y as usize,
+                    },
                 );
+
+                // Only keep the unique tiles, and make a map of where they are placed
+                let (unique_tiles, entries) = map_processor::create_map(&tiled, &settings);
+                map_processor::encode_map(&entries, &settings, &mut final_data);
+
+                // Process all the tiles and add it to final_data
+                for tile in unique_tiles {
+                    let mut paletted = process_tile_paletted(&tile, &settings);
+
+                    final_data.image_data.append(&mut paletted);
+                }
+            } else {
+                if settings.sprite_mapping.is_some() {
+                    sprite_processor::check_sprite_size(&settings);
+                }
+
+                // Bitmaps are packed row by row instead of tile by tile
+                let tile_length = match settings.layout {
+                    Layout::Tiled => {
+                        settings.size_per_tile.x as usize * settings.size_per_tile.y as usize
+                    }
+                    Layout::Bitmap => {
+                        check_bitmap_width(&settings);
+
+                        settings.frame_size.unwrap_or(settings.image_size).x
+                    }
+                };
+
+                // Process all the tiles of every frame
+                let frames: Vec<Vec<u8>> = arrange_frames(&indices, &settings, &mut final_data)
+                    .into_iter()
+                    .map(|frame| {
+                        frame
+                            .chunks(tile_length)
+                            .flat_map(|tile| process_tile_paletted(tile, &settings))
+                            .collect()
+                    })
+                    .collect();
+
+                match settings.sprite_mapping {
+                    Some(SpriteMapping::TwoD) => {
+                        let (charblock, offsets) =
+                            sprite_processor::place_sprites_2d(&frames, &settings);
+
+                        final_data.image_data = charblock;
+
+                        if settings.frame_table {
+                            final_data.sections.push(Section {
+                                name: "frames".to_string(),
+                                data: offsets
+                                    .iter()
+                                    .chain([final_data.image_data.len() as u32].iter())
+                                    .flat_map(|offset| offset.to_le_bytes())
+                                    .collect(),
+                            });
+                        }
+                    }
+                    _ => add_frames(&mut final_data, frames, &settings),
+                }
+            }
+
+            // Mode 7 stores the map and the tiles together
+            if settings.include_map_data && settings.map_format == MapFormat::Mode7 {
+                map_processor::interleave_mode7(&mut final_data, &settings);
             }
 
+            // Convert all the colors into palette into the color encoding
+            final_data.palette_data =
+                color_processor::encode_palette(&palette, &settings.get_palette_encoder());
+
+            final_data.palette = palette;
+
             return final_data;
         }
         BitsPerPixel::Bpp16 => {
-            // TODO: allow splitting bitmap into multiple images
-
-            for color in data {
-                final_data.image_data.append(
-                    &mut create_16bit_color_argb16(
-                        color.r as u16,
-                        color.g as u16,
-                        color.b as u16,
-                        color.a as u16,
-                    )
-                    .to_le_bytes()
-                    .to_vec(),
-                );
+            let mut frames: Vec<Vec<u8>> = Vec::new();
+
+            for frame in arrange_frames(&data, &settings, &mut final_data) {
+                let mut encoded: Vec<u8> = Vec::new();
+
+                for mut color in frame {
+                    // The transparent color doesn't get the alpha bit
+                    if Some(color) == settings.transparent_color {
+                        color.a = 0;
+                    }
+
+                    encoded.append(&mut color_processor::encode_color(
+                        &color,
+                        &settings.get_direct_color_encoder(),
+                    ));
+                }
+
+                frames.push(encoded);
             }
 
+            add_frames(&mut final_data, frames, &settings);
+
             return final_data;
         }
     }
 }
 
+/// Make every fully transparent pixel the transparent color (so it ends up at palette index 0), and every other pixel opaque.
+/// If no transparent color is set but the image has transparent pixels, fully transparent black is used as the transparent color
+pub fn apply_transparency(data: Vec<Color>, settings: TileSettings) -> (Vec<Color>, TileSettings) {
+    let transparent_color = settings.transparent_color.or_else(|| {
+        data.iter().any(|color| color.a == 0).then_some(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        })
+    });
+
+    let data = data
+        .into_iter()
+        .map(|color| match color.a {
+            0 => transparent_color.unwrap(),
+            // Palettes don't store alpha, so partially transparent pixels are treated as opaque
+            _ => Color { a: 255, ..color },
+        })
+        .collect();
+
+    (
+        data,
+        TileSettings {
+            transparent_color,
+            ..settings
+        },
+    )
+}
+
+/// Cut the frames out of a sprite sheet, going left to right, then top to bottom. Without a frame size, the whole image is
+/// one frame
+pub fn split_frames<T: Clone>(data: &[T], settings: &TileSettings) -> Vec<Vec<T>> {
+    let size = settings.image_size;
+
+    let Some(frame_size) = settings.frame_size else {
+        return vec![data[..size.x * size.y].to_vec()];
+    };
+
+    let margin = settings.frame_margin;
+    let step = Vector2 {
+        x: frame_size.x + settings.frame_spacing.x,
+        y: frame_size.y + settings.frame_spacing.y,
+    };
+
+    // The spacing is only between frames, so the last frame of each row and column doesn't need it
+    let columns = (size.x.saturating_sub(margin.x) + settings.frame_spacing.x) / step.x;
+    let rows = (size.y.saturating_sub(margin.y) + settings.frame_spacing.y) / step.y;
+
+    let frame_count = settings.frame_count.unwrap_or(columns * rows);
+
+    if frame_count == 0 || frame_count > columns * rows {
+        panic!(
+            "Error: the image only fits {} frames of {}x{} pixels, but {} frames are needed!",
+            columns * rows,
+            frame_size.x,
+            frame_size.y,
+            frame_count.max(1)
+        );
+    }
+
+    (0..frame_count)
+        .map(|frame| {
+            let x = margin.x + (frame % columns) * step.x;
+            let y = margin.y + (frame / columns) * step.y;
+
+            (y..y + frame_size.y)
+                .flat_map(|row| data[row * size.x + x..row * size.x + x + frame_size.x].to_vec())
+                .collect()
+        })
+        .collect()
+}
+
+/// Only keep the first of any identical frames (and frames that are flipped horizontally, if settings.dedupe_flipped_frames
+/// is set). The frame index table has the unique frame each frame of the sheet uses as 16 bit numbers, with the highest
+/// bit set for flipped frames
+fn dedupe_frames<T: Clone + Eq + Hash>(
+    frames: Vec<Vec<T>>,
+    settings: &TileSettings,
+) -> (Vec<Vec<T>>, Vec<u8>) {
+    let frame_size = settings.frame_size.unwrap_or(settings.image_size);
+
+    let mut unique_frames: Vec<Vec<T>> = Vec::new();
+    let mut frame_indices: HashMap<Vec<T>, usize> = HashMap::new();
+    let mut frame_index_table: Vec<u8> = Vec::new();
+
+    for frame in frames {
+        let flipped = settings
+            .dedupe_flipped_frames
+            .then(|| map_processor::flip_tile(&frame, frame_size, true, false));
+
+        let entry: u16 = if let Some(&index) = frame_indices.get(&frame) {
+            index as u16
+        } else if let Some(&index) = flipped.and_then(|flipped| frame_indices.get(&flipped)) {
+            index as u16 | 0x8000
+        } else {
+            frame_indices.insert(frame.clone(), unique_frames.len());
+            unique_frames.push(frame);
+
+            (unique_frames.len() - 1) as u16
+        };
+
+        frame_index_table.extend(entry.to_le_bytes());
+    }
+
+    (unique_frames, frame_index_table)
+}
+
+/// Split the image into frames, and put the pixels of every frame in the order of the layout. Each frame is tiled on its
+/// own, so the tiles of a frame are always together (like a metatile the size of the frame)
+fn arrange_frames<T: Clone + Eq + Hash>(
+    data: &[T],
+    settings: &TileSettings,
+    final_data: &mut FinishedRawData,
+) -> Vec<Vec<T>> {
+    let frame_size = settings.frame_size.unwrap_or(settings.image_size);
+    let mut frames = split_frames(data, settings);
+
+    if settings.dedupe_frames || settings.dedupe_flipped_frames {
+        let (unique_frames, frame_index_table) = dedupe_frames(frames, settings);
+
+        final_data.sections.push(Section {
+            name: "frameidx".to_string(),
+            data: frame_index_table,
+        });
+
+        frames = unique_frames;
+    }
+
+    frames
+        .into_iter()
+        .map(|frame| match settings.layout {
+            Layout::Bitmap => frame,
+            Layout::Tiled => {
+                let tile_size = Vector2 {
+                    x: settings.size_per_tile.x as usize,
+                    y: settings.size_per_tile.y as usize,
+                };
+
+                // Split the frame into tiles, and group metatiles together
+                let tiled = tile(&frame, frame_size, tile_size);
+
+                tile_sequentially(
+                    &tiled,
+                    Vector2 {
+                        x: frame_size.x / tile_size.x,
+                        y: frame_size.y / tile_size.y,
+                    },
+                    settings.metatile_size,
+                )
+                .concat()
+            }
+        })
+        .collect()
+}
+
+/// Make sure every row of a paletted bitmap is a whole number of 16 bit units, since VRAM can't be written one byte at a
+/// time (so 8bpp bitmaps need an even width, and 4bpp bitmaps a width that is a multiple of 4)
+fn check_bitmap_width(settings: &TileSettings) {
+    let width = settings.frame_size.unwrap_or(settings.image_size).x;
+    let pixels_per_unit = 16 / settings.bpp.to_num();
+
+    if !width.is_multiple_of(pixels_per_unit) {
+        panic!(
+            "Error: {} bits per pixel bitmaps must be a multiple of {} pixels wide, but the image is {} pixels wide!",
+            settings.bpp.to_num(),
+            pixels_per_unit,
+            width
+        );
+    }
+}
+
+/// Add the data of the frames to final_data, either one after another or each in a section of its own. The frame table
+/// has the offset of every frame from the start of the first one, and then the total length, as 32 bit numbers
+fn add_frames(final_data: &mut FinishedRawData, frames: Vec<Vec<u8>>, settings: &TileSettings) {
+    let mut frame_table: Vec<u8> = Vec::new();
+    let mut offset: u32 = 0;
+
+    for (index, mut frame) in frames.into_iter().enumerate() {
+        frame_table.extend(offset.to_le_bytes());
+        offset += frame.len() as u32;
+
+        if settings.separate_frames {
+            final_data.sections.push(Section {
+                name: format!("frame{}", index),
+                data: frame,
+            });
+        } else {
+            final_data.image_data.append(&mut frame);
+        }
+    }
+
+    if settings.frame_table {
+        frame_table.extend(offset.to_le_bytes());
+
+        final_data.sections.push(Section {
+            name: "frames".to_string(),
+            data: frame_table,
+        });
+    }
+}
+
+/// Cut the same rectangle out of every frame of an image (where the frames are stacked on top of each other)
+pub fn crop_frames<T: Clone>(
+    data: &[T],
+    frame_size: Vector2<usize>,
+    position: Vector2<usize>,
+    size: Vector2<usize>,
+) -> Vec<T> {
+    if position.x + size.x > frame_size.x || position.y + size.y > frame_size.y {
+        panic!(
+            "Error: the crop rectangle doesn't fit in the {}x{} image!",
+            frame_size.x, frame_size.y
+        );
+    }
+
+    data.chunks(frame_size.x * frame_size.y)
+        .flat_map(|frame| {
+            (position.y..position.y + size.y).flat_map(move |y| {
+                frame[y * frame_size.x + position.x..y * frame_size.x + position.x + size.x]
+                    .to_vec()
+            })
+        })
+        .collect()
+}
+
+/// Pad the right and bottom of every frame of an image (where the frames are stacked on top of each other) with the
+/// given item, up to the next multiple of the tile size. Returns the padded data and the new size of each frame
+pub fn pad_frames<T: Clone>(
+    data: &[T],
+    frame_size: Vector2<usize>,
+    tile_size: Vector2<usize>,
+    item: T,
+) -> (Vec<T>, Vector2<usize>) {
+    let padded_size = Vector2 {
+        x: frame_size.x.next_multiple_of(tile_size.x),
+        y: frame_size.y.next_multiple_of(tile_size.y),
+    };
+
+    let padded = data
+        .chunks(frame_size.x * frame_size.y)
+        .flat_map(|frame| {
+            let mut padded: Vec<T> = Vec::with_capacity(padded_size.x * padded_size.y);
+
+            for row in frame.chunks(frame_size.x) {
+                padded.extend_from_slice(row);
+                padded.resize(padded.len() + padded_size.x - frame_size.x, item.clone());
+            }
+
+            padded.resize(padded_size.x * padded_size.y, item.clone());
+            padded
+        })
+        .collect();
+
+    (padded, padded_size)
+}
+
 /// Tile data together, and put them each into a Vec<T>
-fn tile<T: Clone>(
+pub fn tile<T: Clone>(
     data: &Vec<T>,
     total_size: Vector2<usize>,
     tile_size: Vector2<usize>,
@@ -109,11 +479,11 @@ fn tile<T: Clone>(
     );
     assert!(
         total_size.x % tile_size.x == 0,
-        "Error: The x size of the image must be divisable by the size of the tile!"
+        "Error: The x size of the image must be divisable by the size of the tile! (Use --pad-to-tile to pad it)"
     );
     assert!(
         total_size.y % tile_size.y == 0,
-        "Error: The x size of the image must be divisable by the size of the tile!"
+        "Error: The y size of the image must be divisable by the size of the tile! (Use --pad-to-tile to pad it)"
     );
 
     let total_tiles: Vector2<usize> = Vector2 {
@@ -169,23 +539,114 @@ fn tile_sequentially<T: Clone>(
         .collect_vec()
 }
 /// Get the palette of an image by taking the image, then deduping it, and making sure the palette fits the parameters given
-fn get_image_palette(data: &Vec<Color>, settings: &TileSettings) -> Vec<Color> {
+pub fn get_image_palette(data: &Vec<Color>, settings: &TileSettings) -> Vec<Color> {
     let mut palette: Vec<Color> = Vec::new();
     // The transperent color needs to be first
     if let Some(color) = settings.transparent_color {
         palette.push(color);
     }
     palette.append(&mut data.clone());
-    let palette: Vec<Color> = palette.into_iter().unique().collect();
+    let mut palette: Vec<Color> = palette.into_iter().unique().collect();
+
+    sort_palette(&mut palette, data, settings);
 
-    if palette.len() > settings.bpp.get_max_palette_length() {
-        panic!("Warning: palette length is {}, which is longer than {} colors, the max for your selected bit-depth. Please choose a higher bit-depth, or remove some colors from your image.", palette.len(), settings.bpp.get_max_palette_length());
+    if palette.len() > settings.get_max_palette_length() {
+        panic!("Warning: palette length is {}, which is longer than {} colors, the max for your selected bit-depth and target. Please choose a higher bit-depth, or remove some colors from your image.", palette.len(), settings.get_max_palette_length());
     }
 
     palette
 }
 
-// Convert to a palette tile
+/// Sort the palette according to settings.palette_sort, while keeping the transparent color first
+pub fn sort_palette(palette: &mut [Color], data: &[Color], settings: &TileSettings) {
+    let sortable = if settings.transparent_color.is_some() {
+        &mut palette[1..]
+    } else {
+        palette
+    };
+
+    match settings.palette_sort {
+        PaletteSort::None => {}
+        PaletteSort::Luminance => {
+            sortable.sort_by_key(color_processor::get_luminance);
+        }
+        PaletteSort::Hue => {
+            sortable.sort_by_key(|color| {
+                (
+                    color_processor::get_hue(color),
+                    color_processor::get_luminance(color),
+                )
+            });
+        }
+        PaletteSort::Frequency => {
+            let counts = data.iter().counts();
+
+            // The sort is stable, so colors used equally often stay in the order they appear
+            sortable.sort_by_key(|color| std::cmp::Reverse(counts.get(color).copied()));
+        }
+    }
+}
+
+/// Pad the palette with black up to the length requested in the settings
+pub fn pad_palette(palette: &mut Vec<Color>, settings: &TileSettings) {
+    let max_palette_length = settings.get_max_palette_length();
+
+    let length = if settings.pad_palette {
+        // Palettes that are already full (like the 16 colors of the NES's sub-palettes) are left as they are
+        max_palette_length.max(palette.len())
+    } else if let Some(length) = settings.palette_length {
+        length
+    } else {
+        return;
+    };
+
+    if length > max_palette_length {
+        panic!("Error: palette length {} is longer than {} colors, the max for your selected bit-depth and tile format.", length, max_palette_length);
+    }
+    if length < palette.len() {
+        panic!("Error: the palette has {} colors, which doesn't fit in the requested palette length of {}.", palette.len(), length);
+    }
+
+    palette.resize(
+        length,
+        Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+}
+
+/// Get the palette of an indexed image as-is, only dropping unused entries past the end if the palette is too long for the bit-depth
+pub fn get_indexed_palette(
+    indexed: IndexedData,
+    settings: &TileSettings,
+) -> (Vec<Color>, Vec<u32>) {
+    let max_palette_length = settings.get_max_palette_length();
+    let mut palette = indexed.palette;
+
+    if settings.transparent_color.is_some() {
+        println!("Warning: the transparent color is ignored when preserving the palette of an indexed image");
+    }
+
+    if palette.len() > max_palette_length {
+        let highest_index = indexed.indices.iter().copied().max().unwrap_or(0) as usize;
+
+        if highest_index >= max_palette_length {
+            panic!("Error: the image uses palette index {}, which does not fit in the {} colors of your selected bit-depth. Please choose a higher bit-depth, or reorder the palette of your image.", highest_index, max_palette_length);
+        }
+
+        palette.truncate(max_palette_length);
+    }
+
+    let mut indices: Vec<u32> = indexed.indices.into_iter().map(u32::from).collect();
+    apply_starting_palette_index(&mut indices, settings);
+
+    (palette, indices)
+}
+
+/// Convert the colors of an image to indices into the palette
 pub fn convert_to_paletted(
     data: &Vec<Color>,
     settings: &TileSettings,
@@ -193,9 +654,7 @@ pub fn convert_to_paletted(
 ) -> Vec<u32> {
     let mut output: Vec<u32> = Vec::new();
 
-    for i in 0..(settings.size_per_tile.x as usize * settings.size_per_tile.y as usize) {
-        let item = data[i];
-
+    for &item in data {
         if let Some(color_index) = palette.iter().position(|&color| color == item) {
             output.push(color_index as u32);
         } else {
@@ -203,9 +662,15 @@ pub fn convert_to_paletted(
         }
     }
 
-    // Start index at starting_palette_index instead of 0
+    apply_starting_palette_index(&mut output, settings);
+
+    output
+}
+
+/// Start palette indices at starting_palette_index instead of 0
+fn apply_starting_palette_index(indices: &mut [u32], settings: &TileSettings) {
     if settings.starting_palette_index != 0 {
-        for item in output.iter_mut() {
+        for item in indices.iter_mut() {
             *item += settings.starting_palette_index;
 
             if *item > settings.bpp.get_max_palette_length() as u32 {
@@ -213,74 +678,305 @@ pub fn convert_to_paletted(
             }
         }
     }
-
-    return output;
 }
 
-/// This function takes the image data, and the palette that image uses, and converts it to a raw byte array, ready to be read.
-fn process_tile_paletted(
-    data: &Vec<Color>,
-    settings: &TileSettings,
-    palette: &Vec<Color>,
-) -> Vec<u8> {
-    let unprocessed = convert_to_paletted(data, settings, &palette);
-
-    let mut raw_data: FinishedRawData = FinishedRawData {
-        image_data: Vec::new(),
-        palette_data: Vec::new(),
-    };
+/// This function takes the palette indices of a tile, and converts them to a raw byte array, ready to be read.
+pub fn process_tile_paletted(unprocessed: &[u32], settings: &TileSettings) -> Vec<u8> {
+    let max_palette_length = settings.bpp.get_max_palette_length() as u32;
 
     // This is used to make sure we only warn that you have two many colors only once
     // TODO: we should have a function that handles this
     let mut has_warned_too_many_colors = false;
 
-    if settings.bpp == BitsPerPixel::Bpp4 {
-        // We go 2 items at a time
-        for i in 0..unprocessed.len() / 2 {
-            let index = i * 2;
-
-            let mut items = (unprocessed[index], unprocessed[index + 1]);
-
-            // Make sure both items fit within the 16 color limit of 4 bit images
-            if items.0 > 0xF as u32 {
+    // Make sure every item fits within the color limit of the bit-depth
+    let items: Vec<u8> = unprocessed
+        .iter()
+        .map(|&item| {
+            if item >= max_palette_length {
                 if !has_warned_too_many_colors {
-                    println!("Line: {}, Warning: You have too many colors, please use a mode which supports more colors, or remove some colors", line!());
+                    println!("Warning: You have too many colors, please use a mode which supports more colors, or remove some colors");
                     has_warned_too_many_colors = true;
                 }
 
-                items.0 = 0;
+                return 0u8;
             }
-            if items.1 > 0xF as u32 {
-                if !has_warned_too_many_colors {
-                    println!("Line: {}, Warning: You have too many colors, please use a mode which supports more colors, or remove some colors", line!());
-                    has_warned_too_many_colors = true;
-                }
 
-                items.1 = 0;
-            }
+            item as u8
+        })
+        .collect();
+
+    match settings.tile_format {
+        TileFormat::Packed => pack_tile(&items, settings),
+        TileFormat::GameBoy => {
+            // The low bitplane, then the high bitplane of each row
+            let order: Vec<(usize, usize)> = (0..settings.size_per_tile.y as usize)
+                .flat_map(|row| [(row, 0), (row, 1)])
+                .collect();
+
+            pack_tile_planar(&items, settings, &order)
+        }
+        TileFormat::Snes => {
+            // Each pair of bitplanes is interleaved row by row, and the pairs come one after another
+            let order: Vec<(usize, usize)> = (0..settings.bpp.to_num() / 2)
+                .flat_map(|pair| {
+                    (0..settings.size_per_tile.y as usize)
+                        .flat_map(move |row| [(row, pair * 2), (row, pair * 2 + 1)])
+                })
+                .collect();
+
+            pack_tile_planar(&items, settings, &order)
+        }
+        TileFormat::MasterSystem => {
+            // All four bitplanes of each row
+            let order: Vec<(usize, usize)> = (0..settings.size_per_tile.y as usize)
+                .flat_map(|row| [(row, 0), (row, 1), (row, 2), (row, 3)])
+                .collect();
 
-            raw_data.image_data.push(((items.0) | (items.1 << 4)) as u8);
+            pack_tile_planar(&items, settings, &order)
+        }
+        TileFormat::Nes => {
+            // The first bitplane of every row, then the second bitplane of every row
+            let order: Vec<(usize, usize)> = (0..2)
+                .flat_map(|plane| {
+                    (0..settings.size_per_tile.y as usize).map(move |row| (row, plane))
+                })
+                .collect();
+
+            pack_tile_planar(&items, settings, &order)
         }
     }
+}
+
+/// Pack the items of a tile one after another, as many per byte as the bit-depth allows
+pub fn pack_tile(items: &[u8], settings: &TileSettings) -> Vec<u8> {
     if settings.bpp == BitsPerPixel::Bpp8 {
-        raw_data.image_data = unprocessed.into_iter().map(|item| {
-            // Make sure the item fits within the 256 color limit for 8 bit images
-            if item > 0xFF {
-                if !has_warned_too_many_colors {
-                    println!("Warning: You have too many colors, please use a mode which supports more colors, or remove some colors");
-                    has_warned_too_many_colors = true;
-                }
+        return items.to_vec();
+    }
 
-                return 0u8;
+    let bits_per_pixel = settings.bpp.to_num();
+
+    // Go through as many items at a time as fit in a byte
+    items
+        .chunks(8 / bits_per_pixel)
+        .map(|items| {
+            let mut byte: u8 = 0;
+
+            for (i, &item) in items.iter().enumerate() {
+                let shift = match settings.bit_order {
+                    BitOrder::LsbFirst => i * bits_per_pixel,
+                    BitOrder::MsbFirst => 8 - bits_per_pixel * (i + 1),
+                };
+
+                byte |= item << shift;
             }
 
-            item as u8
-        }).collect();
+            byte
+        })
+        .collect()
+}
+
+/// Split the items of a tile into bitplanes, and write one byte per (row, plane) in the given order.
+/// The leftmost pixel of each row is always in the highest bit
+fn pack_tile_planar(items: &[u8], settings: &TileSettings, order: &[(usize, usize)]) -> Vec<u8> {
+    let width = settings.size_per_tile.x as usize;
+
+    order
+        .iter()
+        .map(|&(row, plane)| {
+            items[row * width..(row + 1) * width]
+                .iter()
+                .fold(0u8, |byte, &item| (byte << 1) | ((item >> plane) & 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_images() {
+        // A 4x2 image of 2x2 tiles
+        let data: Vec<u32> = (0..8).collect();
+        let tiles = tile(&data, Vector2::new(4, 2), Vector2::new(2, 2));
+
+        assert_eq!(tiles, vec![vec![0, 1, 4, 5], vec![2, 3, 6, 7]]);
+    }
+
+    #[test]
+    fn arranges_16bpp_frames_into_tiles() {
+        // Two 4x2 frames stacked on top of each other, split into 2x2 tiles
+        let data: Vec<u16> = (0..16).collect();
+        let settings = TileSettings {
+            bpp: BitsPerPixel::Bpp16,
+            size_per_tile: Vector2::new(2, 2),
+            image_size: Vector2::new(4, 4),
+            frame_size: Some(Vector2::new(4, 2)),
+            ..Default::default()
+        };
+
+        let frames = arrange_frames(&data, &settings, &mut FinishedRawData::default());
+
+        assert_eq!(
+            frames,
+            vec![
+                vec![0, 1, 4, 5, 2, 3, 6, 7],
+                vec![8, 9, 12, 13, 10, 11, 14, 15]
+            ]
+        );
+
+        // Bitmaps keep the rows of every frame as they are
+        let frames = arrange_frames(
+            &data,
+            &TileSettings {
+                layout: Layout::Bitmap,
+                ..settings
+            },
+            &mut FinishedRawData::default(),
+        );
+
+        assert_eq!(frames, vec![(0..8).collect_vec(), (8..16).collect_vec()]);
+    }
+
+    #[test]
+    fn splits_frames_with_margins_and_spacing() {
+        // A 7x3 sheet with a 1 pixel margin and 1 pixel of spacing between 2x2 frames
+        let data: Vec<u32> = (0..21).collect();
+        let settings = TileSettings {
+            image_size: Vector2::new(7, 3),
+            frame_size: Some(Vector2::new(2, 2)),
+            frame_margin: Vector2::new(1, 1),
+            frame_spacing: Vector2::new(1, 0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            split_frames(&data, &settings),
+            vec![vec![8, 9, 15, 16], vec![11, 12, 18, 19]]
+        );
+    }
+
+    #[test]
+    fn adds_frames_with_a_frame_table() {
+        let settings = TileSettings {
+            frame_table: true,
+            ..Default::default()
+        };
+        let mut final_data = FinishedRawData::default();
+
+        add_frames(&mut final_data, vec![vec![1, 2], vec![3, 4, 5]], &settings);
+
+        assert_eq!(final_data.image_data, vec![1, 2, 3, 4, 5]);
+        assert_eq!(final_data.sections[0].name, "frames");
+        assert_eq!(
+            final_data.sections[0].data,
+            [0u32, 2, 5]
+                .iter()
+                .flat_map(|offset| offset.to_le_bytes())
+                .collect_vec()
+        );
+    }
+
+    /// A tile whose first row is 0 to 7 and whose second row is all 8 (masked to the bit-depth), in the given tile format
+    fn encode_planar_tile(tile_format: TileFormat, bpp: BitsPerPixel) -> Vec<u8> {
+        let mut tile: Vec<u32> = (0..8).collect();
+        tile.extend([8; 8]);
+        tile.extend([0; 48]);
+
+        let max = (1 << bpp.to_num()) - 1;
+        let tile: Vec<u32> = tile.iter().map(|&item| item & max).collect();
+
+        process_tile_paletted(
+            &tile,
+            &TileSettings {
+                bpp,
+                tile_format,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn encodes_game_boy_tiles() {
+        // The low bitplane, then the high bitplane of each row
+        let tile = encode_planar_tile(TileFormat::GameBoy, BitsPerPixel::Bpp2);
+
+        assert_eq!(tile.len(), 16);
+        assert_eq!(tile[..4], [0x55, 0x33, 0x00, 0x00]);
+        assert!(tile[4..].iter().all(|&byte| byte == 0));
     }
 
-    //for item in palette {
-    //    raw_data.palette_data.append(&mut color_processor::create_16bit_color(item.r as u16, item.g as u16, item.b as u16, item.a as u16).to_le_bytes().into());
-    //}
+    #[test]
+    fn encodes_snes_tiles() {
+        // Bitplanes 0 and 1 of every row, then bitplanes 2 and 3 of every row
+        let tile = encode_planar_tile(TileFormat::Snes, BitsPerPixel::Bpp4);
+
+        assert_eq!(tile.len(), 32);
+        assert_eq!(tile[..4], [0x55, 0x33, 0x00, 0x00]);
+        assert_eq!(tile[16..20], [0x0F, 0x00, 0x00, 0xFF]);
+        assert!(tile[4..16].iter().chain(&tile[20..]).all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn encodes_master_system_tiles() {
+        // All four bitplanes of each row
+        let tile = encode_planar_tile(TileFormat::MasterSystem, BitsPerPixel::Bpp4);
+
+        assert_eq!(tile.len(), 32);
+        assert_eq!(tile[..8], [0x55, 0x33, 0x0F, 0x00, 0x00, 0x00, 0x00, 0xFF]);
+        assert!(tile[8..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn dedupes_identical_and_flipped_frames() {
+        let settings = TileSettings {
+            frame_size: Some(Vector2::new(2, 1)),
+            dedupe_flipped_frames: true,
+            ..Default::default()
+        };
+        let frames = vec![vec![1, 2], vec![1, 2], vec![2, 1], vec![3, 4]];
+
+        let (unique_frames, frame_index_table) = dedupe_frames(frames, &settings);
 
-    return raw_data.image_data;
+        // The third frame is the first one flipped, so its entry has the highest bit set
+        assert_eq!(unique_frames, vec![vec![1, 2], vec![3, 4]]);
+        assert_eq!(frame_index_table, vec![0, 0, 0, 0, 0, 0x80, 1, 0]);
+    }
+
+    #[test]
+    fn crops_every_frame() {
+        // Two 3x2 frames stacked on top of each other
+        let data: Vec<u32> = (0..12).collect();
+
+        let cropped = crop_frames(
+            &data,
+            Vector2::new(3, 2),
+            Vector2::new(1, 0),
+            Vector2::new(2, 1),
+        );
+
+        assert_eq!(cropped, vec![1, 2, 7, 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "the crop rectangle doesn't fit in the 3x2 image")]
+    fn rejects_crops_outside_the_image() {
+        crop_frames(
+            &[0; 6],
+            Vector2::new(3, 2),
+            Vector2::new(2, 0),
+            Vector2::new(2, 2),
+        );
+    }
+
+    #[test]
+    fn pads_every_frame() {
+        // Two 3x1 frames of an animation, padded to 2x2 tiles
+        let data: Vec<u32> = vec![1, 2, 3, 4, 5, 6];
+
+        let (padded, size) = pad_frames(&data, Vector2::new(3, 1), Vector2::new(2, 2), 0);
+
+        assert!(size == Vector2::new(4, 2));
+        assert_eq!(padded, vec![1, 2, 3, 0, 0, 0, 0, 0, 4, 5, 6, 0, 0, 0, 0, 0]);
+    }
 }
@@ -14,6 +14,8 @@ struct Settings {
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum BitsPerPixel {
+    Bpp1,
+    Bpp2,
     Bpp4,
     Bpp8,
     Bpp16,
@@ -28,6 +30,12 @@ impl std::fmt::Display for BitsPerPixel {
 impl BitsPerPixel {
     pub fn from_num(bpp: usize) -> Self {
         match bpp {
+            1 => {
+                return BitsPerPixel::Bpp1;
+            }
+            2 => {
+                return BitsPerPixel::Bpp2;
+            }
             4 => {
                 return BitsPerPixel::Bpp4;
             }
@@ -45,6 +53,12 @@ impl BitsPerPixel {
 
     pub fn to_num(&self) -> usize {
         match self {
+            BitsPerPixel::Bpp1 => {
+                return 1;
+            }
+            BitsPerPixel::Bpp2 => {
+                return 2;
+            }
             BitsPerPixel::Bpp4 => {
                 return 4;
             }
@@ -59,6 +73,8 @@ impl BitsPerPixel {
 
     pub fn get_max_palette_length(&self) -> usize {
         match self {
+            BitsPerPixel::Bpp1 => 2,
+            BitsPerPixel::Bpp2 => 4,
             BitsPerPixel::Bpp4 => 16,
             BitsPerPixel::Bpp8 => 256,
             BitsPerPixel::Bpp16 => {
@@ -68,9 +84,24 @@ impl BitsPerPixel {
     }
 }
 
+/// The order pixels are packed into a byte, for bit-depths smaller than 8
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum BitOrder {
+    /// The leftmost pixel is in the lowest bits (GBA, NDS)
+    LsbFirst,
+    /// The leftmost pixel is in the highest bits (Mega Drive, most fonts)
+    MsbFirst,
+}
+
 impl From<u8> for BitsPerPixel {
     fn from(value: u8) -> Self {
         match value {
+            1 => {
+                return BitsPerPixel::Bpp1;
+            }
+            2 => {
+                return BitsPerPixel::Bpp2;
+            }
             4 => {
                 return BitsPerPixel::Bpp4;
             }
@@ -87,36 +118,447 @@ impl From<u8> for BitsPerPixel {
     }
 }
 
+/// The order the colors of a generated palette are put in (the transparent color always stays first)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum PaletteSort {
+    /// Keep the colors in the order they first appear in the image
+    None,
+    /// From darkest to brightest
+    Luminance,
+    /// Grays first, then around the color wheel starting at red
+    Hue,
+    /// From the most used color to the least used color
+    Frequency,
+}
+
+/// Where to take the transparent color from, instead of giving it directly
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TransparentSource {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// The first entry of the palette of an indexed image
+    Palette,
+}
+
+/// Editable formats the palette can be written to, next to the binary palette
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum PaletteExport {
+    /// JASC-PAL (Paint Shop Pro, Aseprite, ...), written to .pal
+    Jasc,
+    /// GIMP palette, written to .gpl
+    Gpl,
+    /// A PNG with a swatch for every color, written to .pal.png
+    Png,
+}
+
+/// How the pixels of a tile are laid out in bytes
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TileFormat {
+    /// Pixels are packed one after another (GBA, NDS, and Mega Drive with --bit-order msb-first)
+    Packed,
+    /// 2bpp planar, the low bitplane byte then the high bitplane byte of each row (Game Boy, Game Boy Color)
+    GameBoy,
+    /// 2, 4 or 8bpp planar, with each pair of bitplanes interleaved row by row (SNES)
+    Snes,
+    /// 4bpp planar, with all four bitplanes of each row one after another (Master System, Game Gear)
+    MasterSystem,
+    /// 2bpp planar, with the first bitplane of every row, then the second bitplane of every row (NES).
+    /// Each 16x16 area gets one of four 3 color palettes, which share the background color
+    Nes,
+}
+
+/// How colors are stored. The bits of each encoding are listed from the highest to the lowest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ColorEncoding {
+    /// 15 bit xbbbbbgggggrrrrr, red in the lowest bits (GBA, NDS, GBC, SNES)
+    Bgr555,
+    /// 15 bit xrrrrrgggggbbbbb
+    Rgb555,
+    /// 16 bit rrrrrggggggbbbbb
+    Rgb565,
+    /// 16 bit abbbbbgggggrrrrr, with the alpha bit on top of BGR555 (GBA and NDS bitmaps)
+    Abgr1555,
+    /// 16 bit arrrrrgggggbbbbb
+    Argb1555,
+    /// 16 bit aaaarrrrggggbbbb
+    Argb4444,
+    /// 32 bit rrrrrrrrggggggggbbbbbbbbaaaaaaaa, big endian by default so the bytes are in RGBA order
+    Rgba8888,
+    /// 24 bit rrrrrrrrggggggggbbbbbbbb, little endian by default so the bytes are in BGR order
+    Bgr888,
+    /// 9 bit 0000bbb0ggg0rrr0, big endian by default (Mega Drive)
+    MegaDrive,
+    /// 6 bit 00bbggrr, one byte per color (Master System)
+    MasterSystem,
+    /// 12 bit 0000bbbbggggrrrr (Game Gear)
+    GameGear,
+    /// One byte per color, the index of the closest color of the NES's own palette
+    Nes,
+    /// 2 bit shades from 0 (white) to 3 (black), four colors to a byte like the BGP register (Game Boy)
+    GameBoy,
+}
+
+impl ColorEncoding {
+    /// The byte order the encoding uses unless one is given
+    pub fn get_default_endianness(&self) -> Endianness {
+        match self {
+            ColorEncoding::Rgba8888 | ColorEncoding::MegaDrive => Endianness::Big,
+            _ => Endianness::Little,
+        }
+    }
+}
+
+/// How 8 bit color channels are reduced to fewer bits
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ChannelConversion {
+    /// Scale and round down, which makes mid-tones slightly darker
+    Truncate,
+    /// Scale and round to the nearest value, the inverse of the usual expansion back to 8 bits
+    Round,
+    /// Drop the lowest bits
+    Shift,
+}
+
+/// Everything needed to convert a color to bytes
+#[derive(Clone, Copy)]
+pub struct ColorEncoder {
+    pub encoding: ColorEncoding,
+    pub conversion: ChannelConversion,
+    pub endianness: Endianness,
+}
+
+/// The order of the bytes of each color
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// The format of each entry of the map
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum MapFormat {
+    /// 16 bit text background entries (GBA, NDS)
+    Gba,
+    /// 8 bit tile indices (Game Boy)
+    Gb,
+    /// 8 bit tile indices, with a separate attribute map for the palette, VRAM bank, flipping and priority (Game Boy Color)
+    Gbc,
+    /// 16 bit entries with a 10 bit tile index, 3 bit palette, priority and flipping (SNES)
+    Snes,
+    /// 8 bit tile indices in a 128x128 map, interleaved with the 8bpp tiles (SNES Mode 7)
+    Mode7,
+    /// 16 bit big endian entries with an 11 bit tile index, 2 bit palette, flipping and priority (Mega Drive)
+    MegaDrive,
+    /// 16 bit entries with a 9 bit tile index, flipping, 1 bit palette and priority (Master System, Game Gear)
+    MasterSystem,
+    /// 8 bit tile indices (NES), the palettes are in the attribute table
+    Nes,
+}
+
+impl MapFormat {
+    pub fn supports_flipping(&self) -> bool {
+        match self {
+            MapFormat::Gba
+            | MapFormat::Gbc
+            | MapFormat::Snes
+            | MapFormat::MegaDrive
+            | MapFormat::MasterSystem => true,
+            MapFormat::Gb | MapFormat::Mode7 | MapFormat::Nes => false,
+        }
+    }
+
+    pub fn get_max_tile_count(&self) -> usize {
+        match self {
+            MapFormat::Gba => 1024,
+            MapFormat::Gb => 256,
+            MapFormat::Gbc => 512,
+            MapFormat::Snes => 1024,
+            MapFormat::Mode7 => 256,
+            MapFormat::MegaDrive => 2048,
+            MapFormat::MasterSystem => 512,
+            MapFormat::Nes => 256,
+        }
+    }
+}
+
+/// Which tiles are merged together when creating the map
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum MapReduce {
+    /// Keep every tile, even if it is a duplicate
+    None,
+    /// Merge identical tiles
+    Tiles,
+    /// Merge identical tiles, and tiles that are flipped versions of each other
+    Flipped,
+}
+
+/// The order the pixels of the image are stored in
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Layout {
+    /// Tile by tile (and metatile by metatile)
+    Tiled,
+    /// Row by row, like a framebuffer (GBA Mode 3 and 4, NDS extended bitmap backgrounds)
+    Bitmap,
+}
+
+/// How the tiles of sprites are arranged in VRAM
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SpriteMapping {
+    /// The tiles of each sprite are one after another
+    #[value(name = "1d")]
+    OneD,
+    /// The rows of tiles of each sprite are one row of the 32 tile wide character block apart
+    #[value(name = "2d")]
+    TwoD,
+}
+
+/// How the value of a tile of an attribute map is chosen from its pixels
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum AttributeRule {
+    /// The value most of the pixels have
+    Majority,
+    /// Every pixel must have the same value
+    Strict,
+}
+
+/// The format of an NDS texture
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TextureFormat {
+    /// 8 bits per texel, a 5 bit palette index and 3 bits of alpha
+    A3i5,
+    /// 8 bits per texel, a 3 bit palette index and 5 bits of alpha
+    A5i3,
+    /// 2 bits per texel, 4 colors
+    Palette4,
+    /// 4 bits per texel, 16 colors
+    Palette16,
+    /// 8 bits per texel, 256 colors
+    Palette256,
+    /// 16 bits per texel, direct color with an alpha bit
+    Direct,
+    /// 4x4 texel blocks, each with 2 bits per texel and its own 2 or 4 color palette (written to .pidx.bin)
+    Compressed,
+}
+
+/// A console to make data for, which sets the defaults of the other settings
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Target {
+    /// Game Boy Advance
+    Gba,
+    /// Nintendo DS
+    Nds,
+    /// Game Boy
+    Gb,
+    /// Game Boy Color
+    Gbc,
+    /// Super Nintendo
+    Snes,
+    /// Mega Drive / Genesis
+    Md,
+    /// Master System
+    Sms,
+    /// Nintendo Entertainment System
+    Nes,
+}
+
+/// The settings a target uses, unless they are given on the command line
+pub struct TargetDefaults {
+    pub bpp: BitsPerPixel,
+    pub bit_order: BitOrder,
+    pub tile_format: TileFormat,
+    pub color_encoding: ColorEncoding,
+    pub channel_conversion: ChannelConversion,
+    pub size_per_tile: Vector2<u16>,
+    pub map_format: MapFormat,
+    /// How many colors the background palette memory holds
+    pub max_colors: usize,
+    /// How many sub-palettes a map entry can choose from
+    pub palette_count: usize,
+    /// How many colors each sub-palette has
+    pub colors_per_palette: usize,
+}
+
+impl Target {
+    pub fn get_defaults(&self) -> TargetDefaults {
+        let gba = TargetDefaults {
+            bpp: BitsPerPixel::Bpp4,
+            bit_order: BitOrder::LsbFirst,
+            tile_format: TileFormat::Packed,
+            color_encoding: ColorEncoding::Bgr555,
+            channel_conversion: ChannelConversion::Round,
+            size_per_tile: Vector2 { x: 8, y: 8 },
+            map_format: MapFormat::Gba,
+            max_colors: 256,
+            palette_count: 16,
+            colors_per_palette: 16,
+        };
+
+        match self {
+            Target::Gba | Target::Nds => gba,
+            Target::Gb => TargetDefaults {
+                bpp: BitsPerPixel::Bpp2,
+                tile_format: TileFormat::GameBoy,
+                color_encoding: ColorEncoding::GameBoy,
+                map_format: MapFormat::Gb,
+                max_colors: 4,
+                palette_count: 1,
+                colors_per_palette: 4,
+                ..gba
+            },
+            Target::Gbc => TargetDefaults {
+                bpp: BitsPerPixel::Bpp2,
+                tile_format: TileFormat::GameBoy,
+                map_format: MapFormat::Gbc,
+                max_colors: 32,
+                palette_count: 8,
+                colors_per_palette: 4,
+                ..gba
+            },
+            Target::Snes => TargetDefaults {
+                tile_format: TileFormat::Snes,
+                map_format: MapFormat::Snes,
+                palette_count: 8,
+                ..gba
+            },
+            Target::Md => TargetDefaults {
+                bit_order: BitOrder::MsbFirst,
+                color_encoding: ColorEncoding::MegaDrive,
+                map_format: MapFormat::MegaDrive,
+                max_colors: 64,
+                palette_count: 4,
+                ..gba
+            },
+            Target::Sms => TargetDefaults {
+                tile_format: TileFormat::MasterSystem,
+                color_encoding: ColorEncoding::MasterSystem,
+                map_format: MapFormat::MasterSystem,
+                max_colors: 32,
+                palette_count: 2,
+                ..gba
+            },
+            Target::Nes => TargetDefaults {
+                bpp: BitsPerPixel::Bpp2,
+                tile_format: TileFormat::Nes,
+                color_encoding: ColorEncoding::Nes,
+                map_format: MapFormat::Nes,
+                max_colors: 16,
+                palette_count: 4,
+                colors_per_palette: 4,
+                ..gba
+            },
+        }
+    }
+
+    /// The bit-depths the console can display
+    pub fn get_supported_bpps(&self) -> &'static [BitsPerPixel] {
+        match self {
+            Target::Gba | Target::Nds => &[
+                BitsPerPixel::Bpp1,
+                BitsPerPixel::Bpp2,
+                BitsPerPixel::Bpp4,
+                BitsPerPixel::Bpp8,
+                BitsPerPixel::Bpp16,
+            ],
+            Target::Snes => &[BitsPerPixel::Bpp2, BitsPerPixel::Bpp4, BitsPerPixel::Bpp8],
+            Target::Md | Target::Sms => &[BitsPerPixel::Bpp4],
+            Target::Gb | Target::Gbc | Target::Nes => &[BitsPerPixel::Bpp2],
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TileSettings {
+    pub target: Option<Target>,
     pub bpp: BitsPerPixel,
+    pub bit_order: BitOrder,
+    pub tile_format: TileFormat,
+    pub color_encoding: ColorEncoding,
+    pub direct_color_encoding: ColorEncoding,
+    pub endianness: Option<Endianness>,
+    pub channel_conversion: ChannelConversion,
     pub size_per_tile: Vector2<u16>,
     pub include_map_data: bool,
+    pub map_format: MapFormat,
+    pub map_reduce: MapReduce,
+    pub map_palette: u8,
+    pub map_priority: bool,
     pub transparent_color: Option<Color>,
+    pub transparent_source: Option<TransparentSource>,
     pub starting_palette_index: u32,
     pub image_size: Vector2<usize>,
     pub metatile_size: Vector2<usize>,
     pub output_name: Option<String>,
+    pub preserve_palette: bool,
+    pub palette_sort: PaletteSort,
+    pub pad_palette: bool,
+    pub palette_length: Option<usize>,
+    pub palette_exports: Vec<PaletteExport>,
+    pub texture_format: Option<TextureFormat>,
+    pub palette0_transparent: bool,
+    pub layout: Layout,
+    pub frame_size: Option<Vector2<usize>>,
+    pub frame_margin: Vector2<usize>,
+    pub frame_spacing: Vector2<usize>,
+    pub frame_count: Option<usize>,
+    pub separate_frames: bool,
+    pub frame_table: bool,
+    pub animation: bool,
+    pub dedupe_frames: bool,
+    pub dedupe_flipped_frames: bool,
+    pub sprite_mapping: Option<SpriteMapping>,
+    pub layers: Vec<String>,
+    pub skip_layers: Vec<String>,
+    pub attribute_image: Option<String>,
+    pub attribute_layer: Option<String>,
+    pub attribute_colors: Vec<(Color, u8)>,
+    pub attribute_rule: AttributeRule,
+    pub font: bool,
+    pub charset: Option<String>,
+    pub first_character: u32,
+    pub glyph_spacing: u8,
+    pub crop: Option<(Vector2<usize>, Vector2<usize>)>,
+    pub pad_to_tile: bool,
 }
 
 impl From<&TileCLI> for TileSettings {
     fn from(value: &TileCLI) -> Self {
+        // Settings that aren't given use the defaults of the target (which are the GBA's if there is no target)
+        let defaults = value.target.unwrap_or(Target::Gba).get_defaults();
+
         Self {
-            bpp: BitsPerPixel::from(value.bpp),
-            size_per_tile: Vector2 {
-                x: value.size_per_tile[0] as u16,
-                y: value.size_per_tile[1] as u16,
+            target: value.target,
+            bpp: value.bpp.map(BitsPerPixel::from).unwrap_or(defaults.bpp),
+            bit_order: value.bit_order.unwrap_or(defaults.bit_order),
+            tile_format: value.tile_format.unwrap_or(defaults.tile_format),
+            color_encoding: value.color_encoding.unwrap_or(defaults.color_encoding),
+            direct_color_encoding: value.direct_color_encoding,
+            // Every encoding has its own byte order unless one is given
+            endianness: value.endianness,
+            // Without a target, truncate like impr always has
+            channel_conversion: match (value.channel_conversion, value.target) {
+                (Some(channel_conversion), _) => channel_conversion,
+                (None, Some(_)) => defaults.channel_conversion,
+                (None, None) => ChannelConversion::Truncate,
             },
-            include_map_data: value.include_map_data,
-            transparent_color: {
-                match value.transparent_color {
-                    Some(value) => {
-                        // We use big endian because red needs to be the first number
-                        Some(Color::from(value.to_be_bytes()))
-                    }
-                    None => None,
-                }
+            size_per_tile: match value.size_per_tile {
+                Some(ref size_per_tile) => Vector2 {
+                    x: size_per_tile[0] as u16,
+                    y: size_per_tile[1] as u16,
+                },
+                None => defaults.size_per_tile,
             },
+            include_map_data: value.include_map_data,
+            map_format: value.map_format.unwrap_or(defaults.map_format),
+            map_reduce: value.map_reduce,
+            map_palette: value.map_palette,
+            map_priority: value.map_priority,
+            // We use big endian because red needs to be the first number
+            transparent_color: value
+                .transparent_color
+                .map(|value| Color::from(value.to_be_bytes())),
+            transparent_source: value.transparent_from,
             starting_palette_index: value.starting_palette_index,
             image_size: Vector2 { x: 0, y: 0 },
             metatile_size: Vector2 {
@@ -124,6 +566,230 @@ impl From<&TileCLI> for TileSettings {
                 y: value.size_per_metatile[1],
             },
             output_name: value.output_name.clone(),
+            preserve_palette: value.preserve_palette,
+            palette_sort: value.palette_sort,
+            pad_palette: value.pad_palette,
+            palette_length: value.palette_length,
+            palette_exports: value.palette_export.clone(),
+            texture_format: value.texture_format,
+            palette0_transparent: value.palette0_transparent,
+            layout: value.layout.unwrap_or(match value.bpp {
+                // Direct color images have always been bitmaps
+                Some(16) => Layout::Bitmap,
+                _ => Layout::Tiled,
+            }),
+            frame_size: value.frame_size.as_ref().map(|frame_size| Vector2 {
+                x: frame_size[0],
+                y: frame_size[1],
+            }),
+            frame_margin: Vector2 {
+                x: value.frame_margin[0],
+                y: value.frame_margin[1],
+            },
+            frame_spacing: Vector2 {
+                x: value.frame_spacing[0],
+                y: value.frame_spacing[1],
+            },
+            frame_count: value.frame_count,
+            separate_frames: value.separate_frames,
+            frame_table: value.frame_table,
+            animation: value.animation,
+            dedupe_frames: value.dedupe_frames,
+            dedupe_flipped_frames: value.dedupe_flipped_frames,
+            sprite_mapping: value.sprite_mapping,
+            layers: value.layers.clone(),
+            skip_layers: value.skip_layers.clone(),
+            attribute_image: value.attribute_image.clone(),
+            attribute_layer: value.attribute_layer.clone(),
+            attribute_colors: value.attribute_colors.clone(),
+            attribute_rule: value.attribute_rule,
+            font: value.font,
+            charset: value.charset.clone(),
+            first_character: value.first_character,
+            glyph_spacing: value.glyph_spacing,
+            crop: value.crop.as_ref().map(|crop| {
+                (
+                    Vector2 {
+                        x: crop[0],
+                        y: crop[1],
+                    },
+                    Vector2 {
+                        x: crop[2],
+                        y: crop[3],
+                    },
+                )
+            }),
+            pad_to_tile: value.pad_to_tile,
+        }
+    }
+}
+
+impl TileSettings {
+    /// The encoder for the colors of the palette
+    pub fn get_palette_encoder(&self) -> ColorEncoder {
+        self.get_encoder(self.color_encoding)
+    }
+
+    /// The encoder for the colors of 16 bits per pixel (direct color) images
+    pub fn get_direct_color_encoder(&self) -> ColorEncoder {
+        self.get_encoder(self.direct_color_encoding)
+    }
+
+    fn get_encoder(&self, encoding: ColorEncoding) -> ColorEncoder {
+        ColorEncoder {
+            encoding,
+            conversion: self.channel_conversion,
+            endianness: self.endianness.unwrap_or(encoding.get_default_endianness()),
+        }
+    }
+
+    /// The most colors the whole palette can have, counting every sub-palette
+    pub fn get_max_palette_length(&self) -> usize {
+        let length = match self.tile_format {
+            // Four sub-palettes of 4 colors each
+            TileFormat::Nes => 16,
+            _ => self.bpp.get_max_palette_length(),
+        };
+
+        match self.target {
+            Some(target) => length.min(target.get_defaults().max_colors),
+            None => length,
+        }
+    }
+
+    /// Make sure the settings can be used together
+    pub fn validate(&self) {
+        if let Some(target) = self.target {
+            if !target.get_supported_bpps().contains(&self.bpp) {
+                panic!(
+                    "Error: the selected target doesn't support {} bits per pixel!",
+                    self.bpp.to_num()
+                );
+            }
+
+            self.validate_palette_limits(target);
+        }
+
+        let supported_bpps: &[BitsPerPixel] = match self.tile_format {
+            TileFormat::Packed => &[
+                BitsPerPixel::Bpp1,
+                BitsPerPixel::Bpp2,
+                BitsPerPixel::Bpp4,
+                BitsPerPixel::Bpp8,
+                BitsPerPixel::Bpp16,
+            ],
+            TileFormat::GameBoy | TileFormat::Nes => &[BitsPerPixel::Bpp2],
+            TileFormat::Snes => &[BitsPerPixel::Bpp2, BitsPerPixel::Bpp4, BitsPerPixel::Bpp8],
+            TileFormat::MasterSystem => &[BitsPerPixel::Bpp4],
+        };
+
+        if !supported_bpps.contains(&self.bpp) {
+            panic!(
+                "Error: the selected tile format doesn't support {} bits per pixel!",
+                self.bpp.to_num()
+            );
+        }
+
+        if self.tile_format != TileFormat::Packed && self.size_per_tile.x != 8 {
+            panic!("Error: planar tiles must be 8 pixels wide!");
+        }
+
+        if self.include_map_data
+            && self.map_format == MapFormat::Mode7
+            && (self.bpp != BitsPerPixel::Bpp8 || self.tile_format != TileFormat::Packed)
+        {
+            panic!("Error: Mode 7 tiles are always 8 bits per pixel and packed, please use --bpp 8 --tile-format packed");
+        }
+
+        if self.layout == Layout::Bitmap && self.tile_format != TileFormat::Packed {
+            panic!(
+                "Error: planar tiles can't be stored as a bitmap, please use --tile-format packed"
+            );
+        }
+
+        if self.layout == Layout::Bitmap && self.include_map_data {
+            panic!("Error: bitmaps don't have map data, please use --layout tiled");
+        }
+
+        if let Some(sprite_mapping) = self.sprite_mapping {
+            if self.bpp != BitsPerPixel::Bpp4 && self.bpp != BitsPerPixel::Bpp8 {
+                panic!("Error: sprites are either 4 or 8 bits per pixel!");
+            }
+
+            if self.layout != Layout::Tiled
+                || self.tile_format != TileFormat::Packed
+                || self.size_per_tile != Vector2::new(8, 8)
+            {
+                panic!("Error: sprites are made of 8x8 packed tiles!");
+            }
+
+            if self.include_map_data {
+                panic!("Error: sprites don't have map data!");
+            }
+
+            if sprite_mapping == SpriteMapping::TwoD
+                && (self.separate_frames || self.metatile_size != Vector2::new(1, 1))
+            {
+                panic!("Error: 2D sprite mapping can't be used with separate frames or metatiles!");
+            }
+        }
+
+        if self.font {
+            if !matches!(
+                self.bpp,
+                BitsPerPixel::Bpp1 | BitsPerPixel::Bpp2 | BitsPerPixel::Bpp4
+            ) {
+                panic!("Error: fonts are 1, 2 or 4 bits per pixel!");
+            }
+
+            if self.dedupe_frames || self.dedupe_flipped_frames {
+                panic!("Error: the glyphs of a font can't be deduplicated!");
+            }
+        }
+
+        if self.texture_format.is_some() && self.include_map_data {
+            panic!("Error: textures don't have map data!");
+        }
+    }
+
+    /// Make sure the palette fits in the palette memory of the target
+    fn validate_palette_limits(&self, target: Target) {
+        if self.bpp == BitsPerPixel::Bpp16 {
+            return;
+        }
+
+        let limits = target.get_defaults();
+        let max_palette_length = self.get_max_palette_length();
+
+        if let Some(length) = self.palette_length {
+            if length > max_palette_length {
+                panic!(
+                    "Error: the selected target only has room for {} colors, but --palette-length is {}!",
+                    max_palette_length, length
+                );
+            }
+        }
+
+        if self.starting_palette_index as usize >= max_palette_length {
+            panic!(
+                "Error: the selected target only has room for {} colors, so --starting-palette-index must be less than that!",
+                max_palette_length
+            );
+        }
+
+        // Palettes that fit in one sub-palette can be put in any of them, larger ones use the whole palette memory
+        if self.bpp.get_max_palette_length() <= limits.colors_per_palette {
+            if self.map_palette as usize >= limits.palette_count {
+                panic!(
+                    "Error: the selected target only has {} palettes, so --map-palette must be less than {}!",
+                    limits.palette_count, limits.palette_count
+                );
+            }
+        } else if self.map_palette != 0 {
+            panic!(
+                "Error: {} bits per pixel tiles use the whole palette, so --map-palette must be 0!",
+                self.bpp.to_num()
+            );
         }
     }
 }
@@ -140,26 +806,61 @@ pub struct TileCLI {
     /// The files to process
     #[arg(short, long, num_args = 1.., required=true)]
     pub files: Vec<String>,
-    /// Bits per pixel that the image will be. 4 & 8 bits per pixel are paletted, while 16 is truecolor mode
+    /// The console to make data for, which sets the defaults for the bit-depth, tile size and formats, and limits the palette to what the console can hold (the GBA's defaults are used if not given)
+    #[arg(long, value_enum)]
+    pub target: Option<Target>,
+    /// Bits per pixel that the image will be. 1, 2, 4 & 8 bits per pixel are paletted, while 16 is truecolor mode [default: 4]
     #[arg(
         long,
-        default_value_t = 4,
-        value_parser = clap::builder::PossibleValuesParser::new(["4", "8", "16"])
+        value_parser = clap::builder::PossibleValuesParser::new(["1", "2", "4", "8", "16"])
             .map(|s| s.parse::<u8>().unwrap()),
     )]
-    pub bpp: u8,
-    /// The size of each basic tile
-    #[arg(long, num_args = 2, default_values_t = [8, 8], value_names = ["x", "y"])]
-    pub size_per_tile: Vec<usize>,
+    pub bpp: Option<u8>,
+    /// The order pixels are packed into each byte for 1, 2 and 4 bits per pixel [default: lsb-first]
+    #[arg(long, value_enum)]
+    pub bit_order: Option<BitOrder>,
+    /// How the pixels of each tile are laid out [default: packed]
+    #[arg(long, value_enum)]
+    pub tile_format: Option<TileFormat>,
+    /// How the colors of the palette are stored [default: bgr555]
+    #[arg(long, value_enum)]
+    pub color_encoding: Option<ColorEncoding>,
+    /// How the colors of 16 bits per pixel (direct color) images are stored
+    #[arg(long, value_enum, default_value_t = ColorEncoding::Abgr1555)]
+    pub direct_color_encoding: ColorEncoding,
+    /// The byte order of the colors (if not given, each color encoding uses its usual byte order)
+    #[arg(long, value_enum)]
+    pub endianness: Option<Endianness>,
+    /// How 8 bit color channels are reduced to the bits of the color encoding [default: round with a target, truncate without one]
+    #[arg(long, value_enum)]
+    pub channel_conversion: Option<ChannelConversion>,
+    /// The size of each basic tile [default: 8 8]
+    #[arg(long, num_args = 2, value_names = ["x", "y"])]
+    pub size_per_tile: Option<Vec<usize>>,
     /// The size of each metatile (useful for keeping sprites that are larger than the basic tile size together)
     #[arg(long, num_args = 2, default_values_t = [1, 1])]
     pub size_per_metatile: Vec<usize>,
     /// If the map data (order in which the tiles are placed) should be included with the other data
     #[arg(long, default_value_t = false)]
     pub include_map_data: bool,
+    /// The format of each entry of the map [default: gba]
+    #[arg(long, value_enum)]
+    pub map_format: Option<MapFormat>,
+    /// Which tiles to merge together when creating the map
+    #[arg(long, value_enum, default_value_t = MapReduce::Tiles)]
+    pub map_reduce: MapReduce,
+    /// The palette (bank) every map entry uses
+    #[arg(long, default_value_t = 0)]
+    pub map_palette: u8,
+    /// Set the priority bit of every map entry (only used by formats that have one)
+    #[arg(long, default_value_t = false)]
+    pub map_priority: bool,
     /// The transparent color to use (will be first in the palette)
     #[arg(long, short, value_parser=maybe_hex::<u32>)]
     pub transparent_color: Option<u32>,
+    /// Take the transparent color from a corner pixel of each image, or from the first palette entry of indexed images
+    #[arg(long, value_enum, conflicts_with = "transparent_color")]
+    pub transparent_from: Option<TransparentSource>,
     /// The starting index of the palette (keep in mind that if starting_palette_index + num_of_colors > max_colors_for_bitdepth, it will give a warning)
     #[arg(long, default_value_t = 0)]
     pub starting_palette_index: u32,
@@ -169,24 +870,214 @@ pub struct TileCLI {
     /// Output filename for the data (only works if you have one file)
     #[arg(long)]
     pub output_name: Option<String>,
+    /// Use the palette and indices of indexed images (PNG, BMP, GIF) as they are stored in the file, keeping the palette order and unused entries
+    #[arg(long, default_value_t = false)]
+    pub preserve_palette: bool,
+    /// How to sort the colors of the generated palette
+    #[arg(long, value_enum, default_value_t = PaletteSort::None)]
+    pub palette_sort: PaletteSort,
+    /// Pad the palette to the full length for the bit-depth (16 colors for 4bpp, 256 colors for 8bpp)
+    #[arg(long, default_value_t = false)]
+    pub pad_palette: bool,
+    /// Pad the palette to this many colors
+    #[arg(long, conflicts_with = "pad_palette")]
+    pub palette_length: Option<usize>,
+    /// Also write the palette in these editable formats
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub palette_export: Vec<PaletteExport>,
+    /// Make an NDS texture instead of tiles. The image is stored texel by texel, and the bit-depth and tile settings are ignored
+    #[arg(long, value_enum)]
+    pub texture_format: Option<TextureFormat>,
+    /// Keep palette index 0 free for transparent texels, for textures that use the palette 0 transparent flag
+    #[arg(long, default_value_t = false, requires = "texture_format")]
+    pub palette0_transparent: bool,
+    /// The order the pixels are stored in [default: bitmap for 16 bits per pixel, tiled otherwise]
+    #[arg(long, value_enum)]
+    pub layout: Option<Layout>,
+    /// Split the image (like a sprite sheet) into frames of this size, which are stored one after another (left to right, then top to bottom)
+    #[arg(long, num_args = 2, value_names = ["x", "y"])]
+    pub frame_size: Option<Vec<usize>>,
+    /// The space around the frames of a sprite sheet, from the top left corner
+    #[arg(long, num_args = 2, value_names = ["x", "y"], default_values_t = [0, 0])]
+    pub frame_margin: Vec<usize>,
+    /// The space between the frames of a sprite sheet
+    #[arg(long, num_args = 2, value_names = ["x", "y"], default_values_t = [0, 0])]
+    pub frame_spacing: Vec<usize>,
+    /// How many frames to take from the sprite sheet, if the last row isn't full [default: as many as fit]
+    #[arg(long, requires = "frame_size")]
+    pub frame_count: Option<usize>,
+    /// Write every frame to its own file (.frame0.bin, .frame1.bin, ...) instead of together in .img.bin
+    #[arg(long, default_value_t = false)]
+    pub separate_frames: bool,
+    /// Write a table with the offset of every frame (and then the total length) as 32 bit numbers to .frames.bin
+    #[arg(long, default_value_t = false)]
+    pub frame_table: bool,
+    /// Read every frame of animated GIF, PNG (APNG) and WebP files, and write how long each frame is shown to .durations.bin (only the first frame is read otherwise)
+    #[arg(long, default_value_t = false)]
+    pub animation: bool,
+    /// Only store identical frames once, and write which frame each frame of the sheet uses as 16 bit numbers to .frameidx.bin
+    #[arg(long, default_value_t = false)]
+    pub dedupe_frames: bool,
+    /// Also store frames that are horizontally flipped versions of an earlier frame only once (the highest bit of their entry in .frameidx.bin is set)
+    #[arg(long, default_value_t = false)]
+    pub dedupe_flipped_frames: bool,
+    /// Make GBA/NDS sprites, checking that every frame is a size sprites can be, and arranging the tiles for this mapping mode
+    #[arg(long, value_enum)]
+    pub sprite_mapping: Option<SpriteMapping>,
+    /// Only flatten these layers of Aseprite files [default: the visible layers]
+    #[arg(long, value_delimiter = ',')]
+    pub layers: Vec<String>,
+    /// Leave these layers of Aseprite files out
+    #[arg(long, value_delimiter = ',')]
+    pub skip_layers: Vec<String>,
+    /// An image the size of the input image, whose colors are turned into one value per tile (like collision) and written to .tileattr.bin
+    #[arg(long, requires = "attribute_colors")]
+    pub attribute_image: Option<String>,
+    /// A layer of a Tiled map to use as the attribute image, instead of as a map
+    #[arg(
+        long,
+        requires = "attribute_colors",
+        conflicts_with = "attribute_image"
+    )]
+    pub attribute_layer: Option<String>,
+    /// The value of each color of the attribute image, like 000000=0,ff0000=1
+    #[arg(long, value_delimiter = ',', value_parser = parse_attribute_color)]
+    pub attribute_colors: Vec<(Color, u8)>,
+    /// How the value of each tile is chosen when its pixels have different values
+    #[arg(long, value_enum, default_value_t = AttributeRule::Majority)]
+    pub attribute_rule: AttributeRule,
+    /// Make a font, where every frame is a glyph. The advance width of every glyph is written to .glyphs.bin, and the glyph of every character to .charmap.bin
+    #[arg(long, default_value_t = false)]
+    pub font: bool,
+    /// A UTF-8 text file with the character of every glyph, in order (line breaks are skipped)
+    #[arg(long, requires = "font", conflicts_with = "first_character")]
+    pub charset: Option<String>,
+    /// The character code of the first glyph, when the glyphs are a range of characters
+    #[arg(long, default_value_t = 32, value_parser=maybe_hex::<u32>)]
+    pub first_character: u32,
+    /// How many pixels to leave after each glyph
+    #[arg(long, default_value_t = 1)]
+    pub glyph_spacing: u8,
+    /// Only use this rectangle of the image (of every frame, for animations)
+    #[arg(long, num_args = 4, value_names = ["x", "y", "width", "height"])]
+    pub crop: Option<Vec<usize>>,
+    /// Pad the right and bottom of the image with the transparent color up to a multiple of the tile size, instead of stopping with an error
+    #[arg(long, default_value_t = false)]
+    pub pad_to_tile: bool,
+}
+
+/// Parse a color and its attribute value, written as rrggbb=value
+fn parse_attribute_color(text: &str) -> Result<(Color, u8), String> {
+    let (color, value) = text
+        .split_once('=')
+        .ok_or(format!("{} should be written as rrggbb=value", text))?;
+
+    let color = u32::from_str_radix(color.trim_start_matches('#'), 16)
+        .map_err(|error| error.to_string())?;
+    let value = maybe_hex::<u8>(value)?;
+    let [_, r, g, b] = color.to_be_bytes();
+
+    Ok((Color { r, g, b, a: 255 }, value))
 }
 
 impl Default for TileSettings {
     fn default() -> Self {
         TileSettings {
+            target: None,
             bpp: BitsPerPixel::Bpp8,
+            bit_order: BitOrder::LsbFirst,
+            tile_format: TileFormat::Packed,
+            color_encoding: ColorEncoding::Bgr555,
+            direct_color_encoding: ColorEncoding::Abgr1555,
+            endianness: None,
+            channel_conversion: ChannelConversion::Truncate,
             size_per_tile: Vector2 { x: 8, y: 8 },
             include_map_data: false,
+            map_format: MapFormat::Gba,
+            map_reduce: MapReduce::Tiles,
+            map_palette: 0,
+            map_priority: false,
             transparent_color: Some(ColorStruct {
                 r: 0xFF,
                 g: 0,
                 b: 0xFF,
                 a: 0xFF,
             }),
+            transparent_source: None,
             starting_palette_index: 0,
             image_size: Vector2 { x: 0, y: 0 },
             metatile_size: Vector2 { x: 1, y: 1 },
             output_name: None,
+            preserve_palette: false,
+            palette_sort: PaletteSort::None,
+            pad_palette: false,
+            palette_length: None,
+            palette_exports: Vec::new(),
+            texture_format: None,
+            palette0_transparent: false,
+            layout: Layout::Tiled,
+            frame_size: None,
+            frame_margin: Vector2 { x: 0, y: 0 },
+            frame_spacing: Vector2 { x: 0, y: 0 },
+            frame_count: None,
+            separate_frames: false,
+            frame_table: false,
+            animation: false,
+            dedupe_frames: false,
+            dedupe_flipped_frames: false,
+            sprite_mapping: None,
+            layers: Vec::new(),
+            skip_layers: Vec::new(),
+            attribute_image: None,
+            attribute_layer: None,
+            attribute_colors: Vec::new(),
+            attribute_rule: AttributeRule::Majority,
+            font: false,
+            charset: None,
+            first_character: 32,
+            glyph_spacing: 1,
+            crop: None,
+            pad_to_tile: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> TileSettings {
+        TileSettings::from(&TileCLI::parse_from(
+            ["impr", "-f", "a.png"].iter().chain(arguments),
+        ))
+    }
+
+    #[test]
+    fn keeps_the_byte_order_of_the_encoding_with_a_target() {
+        let settings = parse(&["--target", "gba", "--color-encoding", "rgba8888"]);
+
+        assert!(settings.get_palette_encoder().endianness == Endianness::Big);
+        assert!(parse(&["--target", "md"]).get_palette_encoder().endianness == Endianness::Big);
+        assert!(parse(&["--target", "gba"]).get_palette_encoder().endianness == Endianness::Little);
+        assert!(
+            parse(&["--target", "md", "--endianness", "little"])
+                .get_palette_encoder()
+                .endianness
+                == Endianness::Little
+        );
+    }
+
+    #[test]
+    fn allows_frame_options_without_a_frame_size() {
+        // Animations and Aseprite files have their frame size in the file
+        for option in [
+            "--dedupe-frames",
+            "--dedupe-flipped-frames",
+            "--frame-table",
+            "--separate-frames",
+            "--font",
+        ] {
+            assert!(TileCLI::try_parse_from(["impr", "-f", "a.aseprite", option]).is_ok());
         }
     }
 }
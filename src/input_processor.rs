@@ -0,0 +1,498 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, Frames,
+};
+
+use crate::{basic_types::*, image_settings::TransparentSource};
+
+/// Find the transparent color of an image from the given source. The palette is the one already read from the file (like
+/// the palette of an Aseprite file), if there is one
+pub fn get_transparent_color(
+    file: &str,
+    data: &[Color],
+    size: Vector2<usize>,
+    source: TransparentSource,
+    palette: Option<&[Color]>,
+) -> Color {
+    // Partially transparent pixels are made opaque by apply_transparency, so the corner color has to be as well to match
+    let corner = |x: usize, y: usize| match data[y * size.x + x] {
+        color if color.a == 0 => color,
+        color => Color { a: 255, ..color },
+    };
+
+    match source {
+        TransparentSource::TopLeft => corner(0, 0),
+        TransparentSource::TopRight => corner(size.x - 1, 0),
+        TransparentSource::BottomLeft => corner(0, size.y - 1),
+        TransparentSource::BottomRight => corner(size.x - 1, size.y - 1),
+        TransparentSource::Palette => match palette
+            .map(|palette| palette.to_vec())
+            .or_else(|| get_indexed_image_data(file).map(|indexed| indexed.palette))
+        {
+            Some(palette) if !palette.is_empty() => palette[0],
+            _ => {
+                panic!(
+                    "Error: {} is not an indexed image, so the transparent color can't be taken from its palette!",
+                    file
+                );
+            }
+        },
+    }
+}
+
+/// Read the palette and palette indices straight from an indexed image, without reordering anything.
+/// Returns None if the file is not an indexed PNG, BMP or GIF
+pub fn get_indexed_image_data(file: &str) -> Option<IndexedData> {
+    let extension = Path::new(file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("png") => read_indexed_png(file),
+        Some("bmp") => read_indexed_bmp(file),
+        Some("gif") => read_indexed_gif(file),
+        _ => None,
+    }
+}
+
+/// Read every frame of an animated GIF, PNG (APNG) or WebP. Returns None if the file is not animated, or only has one frame
+pub fn get_animation(file: &str) -> Option<Animation> {
+    let extension = Path::new(file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    let reader = BufReader::new(File::open(file).ok()?);
+
+    let frames: Frames = match extension.as_deref() {
+        Some("gif") => GifDecoder::new(reader).ok()?.into_frames(),
+        Some("png") => {
+            let decoder = PngDecoder::new(reader).ok()?;
+
+            if !decoder.is_apng() {
+                return None;
+            }
+
+            decoder.apng().into_frames()
+        }
+        Some("webp") => {
+            let decoder = WebPDecoder::new(reader).ok()?;
+
+            if !decoder.has_animation() {
+                return None;
+            }
+
+            decoder.into_frames()
+        }
+        _ => return None,
+    };
+
+    let frames = frames
+        .collect_frames()
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    if frames.len() < 2 {
+        return None;
+    }
+
+    let frame_size = Vector2 {
+        x: frames[0].buffer().width() as usize,
+        y: frames[0].buffer().height() as usize,
+    };
+
+    let mut data: Vec<Color> = Vec::with_capacity(frame_size.x * frame_size.y * frames.len());
+    let mut durations: Vec<f64> = Vec::new();
+
+    for frame in &frames {
+        data.extend(frame.buffer().pixels().map(|pixel| Color::from(pixel.0)));
+
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        durations.push(numerator as f64 / denominator as f64);
+    }
+
+    Some(Animation {
+        data,
+        frame_size,
+        durations,
+    })
+}
+
+/// Unpack rows of 1, 2, 4 or 8 bit indices (leftmost pixel in the highest bits) into one index per byte
+fn unpack_indices(data: &[u8], size: Vector2<usize>, bit_depth: usize, stride: usize) -> Vec<u8> {
+    let mut indices: Vec<u8> = Vec::with_capacity(size.x * size.y);
+    let pixels_per_byte = 8 / bit_depth;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+
+    for y in 0..size.y {
+        let row = &data[y * stride..];
+
+        for x in 0..size.x {
+            let byte = row[x / pixels_per_byte];
+            let shift = 8 - bit_depth * (x % pixels_per_byte + 1);
+
+            indices.push((byte >> shift) & mask);
+        }
+    }
+
+    indices
+}
+
+fn read_indexed_png(file: &str) -> Option<IndexedData> {
+    let mut decoder = png::Decoder::new(File::open(file).ok()?);
+    // We want the indices exactly as they are stored
+    decoder.set_transformations(png::Transformations::IDENTITY);
+
+    let mut reader = decoder
+        .read_info()
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    if reader.info().color_type != png::ColorType::Indexed {
+        return None;
+    }
+
+    let palette_bytes = reader.info().palette.as_ref()?.to_vec();
+    let trns = reader.info().trns.as_ref().map(|trns| trns.to_vec());
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut buffer)
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    let size = Vector2::new(frame.width as usize, frame.height as usize);
+
+    let palette: Vec<Color> = palette_bytes
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(i, rgb)| Color {
+            r: rgb[0],
+            g: rgb[1],
+            b: rgb[2],
+            // Entries without a tRNS value are fully opaque
+            a: trns
+                .as_ref()
+                .and_then(|trns| trns.get(i).copied())
+                .unwrap_or(255),
+        })
+        .collect();
+
+    let indices = unpack_indices(&buffer, size, frame.bit_depth as usize, frame.line_size);
+    check_palette_indices(&indices, palette.len())
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    Some(IndexedData { indices, palette })
+}
+
+/// Make sure every palette index is in the palette
+fn check_palette_indices(indices: &[u8], palette_length: usize) -> Result<(), String> {
+    match indices
+        .iter()
+        .find(|&&index| index as usize >= palette_length)
+    {
+        Some(&index) => Err(format!(
+            "a pixel uses palette index {}, but the palette only has {} colors",
+            index, palette_length
+        )),
+        None => Ok(()),
+    }
+}
+
+fn read_indexed_bmp(file: &str) -> Option<IndexedData> {
+    let bytes = std::fs::read(file).ok()?;
+
+    parse_indexed_bmp(&bytes)
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error))
+}
+
+/// Read the palette and indices of an uncompressed 1, 4 or 8 bit BMP. Returns Ok(None) if it is a BMP that isn't indexed
+fn parse_indexed_bmp(bytes: &[u8]) -> Result<Option<IndexedData>, String> {
+    let read_u16 = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let read_u32 = |offset: usize| {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    };
+
+    if !bytes.starts_with(b"BM") {
+        return Ok(None);
+    }
+    if bytes.len() < 18 {
+        return Err("the BMP header is cut off".to_string());
+    }
+
+    // OS/2 BMPs have a smaller header with other field sizes, which are left to the image crate
+    let header_size = read_u32(14) as usize;
+
+    if header_size < 40 {
+        return Ok(None);
+    }
+    if bytes.len() < 54 {
+        return Err("the BMP header is cut off".to_string());
+    }
+
+    let pixel_offset = read_u32(10) as usize;
+    let width = read_u32(18) as i32;
+    let height = read_u32(22) as i32;
+    let bit_depth = read_u16(28) as usize;
+    let compression = read_u32(30);
+    let colors_used = read_u32(46) as usize;
+
+    if !matches!(bit_depth, 1 | 4 | 8) {
+        return Ok(None);
+    }
+    if compression != 0 {
+        return Err("only uncompressed indexed BMP files are supported".to_string());
+    }
+    if width <= 0 || height == 0 {
+        return Err(format!("the image is {}x{} pixels", width, height));
+    }
+
+    let palette_length = if colors_used == 0 {
+        1 << bit_depth
+    } else {
+        colors_used
+    };
+
+    if palette_length > 1 << bit_depth {
+        return Err(format!(
+            "the palette has {} colors, but a {} bit image can only have {}",
+            palette_length,
+            bit_depth,
+            1 << bit_depth
+        ));
+    }
+
+    // The palette is stored as BGRX right after the header
+    let palette_start = 14 + header_size;
+    let palette: Vec<Color> = bytes
+        .get(palette_start..palette_start + palette_length * 4)
+        .ok_or("the palette is cut off")?
+        .chunks_exact(4)
+        .map(|bgrx| Color {
+            r: bgrx[2],
+            g: bgrx[1],
+            b: bgrx[0],
+            a: 255,
+        })
+        .collect();
+
+    let size = Vector2::new(width as usize, height.unsigned_abs() as usize);
+    // Each row is padded to 4 bytes
+    let stride = (size.x * bit_depth).div_ceil(32) * 4;
+
+    let pixels = stride
+        .checked_mul(size.y)
+        .and_then(|length| bytes.get(pixel_offset..pixel_offset.checked_add(length)?))
+        .ok_or("the pixel data is cut off")?;
+
+    let mut indices = unpack_indices(pixels, size, bit_depth, stride);
+    check_palette_indices(&indices, palette.len())?;
+
+    // Positive heights mean the rows are stored bottom to top
+    if height > 0 {
+        indices = indices
+            .chunks_exact(size.x)
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+    }
+
+    Ok(Some(IndexedData { indices, palette }))
+}
+
+fn read_indexed_gif(file: &str) -> Option<IndexedData> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+
+    let mut decoder = options
+        .read_info(File::open(file).ok()?)
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    let size = Vector2::new(decoder.width() as usize, decoder.height() as usize);
+    let background = decoder.bg_color().unwrap_or(0) as u8;
+    let global_palette = decoder.global_palette().map(|palette| palette.to_vec());
+
+    let frame = decoder
+        .read_next_frame()
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error))?;
+
+    // The first frame does not have to cover the whole image
+    let mut indices = vec![background; size.x * size.y];
+
+    for y in 0..frame.height as usize {
+        for x in 0..frame.width as usize {
+            let position = Vector2::new(frame.left as usize + x, frame.top as usize + y);
+
+            if position.x < size.x && position.y < size.y {
+                indices[position.y * size.x + position.x] =
+                    frame.buffer[y * frame.width as usize + x];
+            }
+        }
+    }
+
+    let palette: Vec<Color> = frame
+        .palette
+        .clone()
+        .or(global_palette)?
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(i, rgb)| Color {
+            r: rgb[0],
+            g: rgb[1],
+            b: rgb[2],
+            a: if frame.transparent == Some(i as u8) {
+                0
+            } else {
+                255
+            },
+        })
+        .collect();
+
+    // This includes the background color, which fills the parts the first frame doesn't cover
+    check_palette_indices(&indices, palette.len())
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+    Some(IndexedData { indices, palette })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a bottom-up 4 bit BMP with the given palette (as BGRX) and rows of indices
+    fn build_bmp(palette: &[[u8; 4]], rows: &[&[u8]]) -> Vec<u8> {
+        let width = rows[0].len();
+        let stride = (width * 4).div_ceil(32) * 4;
+        let pixel_offset = 54 + palette.len() * 4;
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(b"BM");
+        bytes.extend(((pixel_offset + stride * rows.len()) as u32).to_le_bytes());
+        bytes.extend([0; 4]);
+        bytes.extend((pixel_offset as u32).to_le_bytes());
+        bytes.extend(40u32.to_le_bytes());
+        bytes.extend((width as i32).to_le_bytes());
+        bytes.extend((rows.len() as i32).to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(4u16.to_le_bytes());
+        bytes.extend([0; 16]);
+        bytes.extend((palette.len() as u32).to_le_bytes());
+        bytes.extend([0; 4]);
+
+        for bgrx in palette {
+            bytes.extend(bgrx);
+        }
+
+        for row in rows.iter().rev() {
+            let mut packed: Vec<u8> = row
+                .chunks(2)
+                .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+                .collect();
+            packed.resize(stride, 0);
+            bytes.extend(packed);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn reads_indexed_bmp() {
+        let bytes = build_bmp(&[[0, 0, 255, 0], [255, 0, 0, 0]], &[&[0, 1, 1], &[1, 0, 0]]);
+        let indexed = parse_indexed_bmp(&bytes).unwrap().unwrap();
+
+        assert_eq!(indexed.indices, vec![0, 1, 1, 1, 0, 0]);
+        assert_eq!(
+            indexed.palette,
+            vec![
+                Color {
+                    r: 255,
+                    g: 0,
+                    b: 0,
+                    a: 255
+                },
+                Color {
+                    r: 0,
+                    g: 0,
+                    b: 255,
+                    a: 255
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_bmp() {
+        let bytes = build_bmp(&[[0, 0, 0, 0], [255, 255, 255, 0]], &[&[0, 1], &[1, 0]]);
+
+        for length in [2, 40, 58, bytes.len() - 1] {
+            assert!(parse_indexed_bmp(&bytes[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_indices_outside_the_palette() {
+        let bytes = build_bmp(&[[0, 0, 0, 0], [255, 255, 255, 0]], &[&[0, 2]]);
+
+        assert!(parse_indexed_bmp(&bytes).is_err());
+    }
+
+    #[test]
+    fn leaves_os2_bmp_to_the_image_crate() {
+        let mut bytes = build_bmp(&[[0, 0, 0, 0], [255, 255, 255, 0]], &[&[0, 1], &[1, 0]]);
+        // A 12 byte BITMAPCOREHEADER
+        bytes[14..18].copy_from_slice(&12u32.to_le_bytes());
+
+        assert!(parse_indexed_bmp(&bytes).unwrap().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "a pixel uses palette index 7, but the palette only has 2 colors")]
+    fn rejects_png_indices_outside_the_palette() {
+        let file = std::env::temp_dir().join("impr_png_indices_outside_the_palette.png");
+
+        let mut encoder = png::Encoder::new(File::create(&file).unwrap(), 8, 8);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(vec![0, 0, 0, 255, 255, 255]);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[7; 64])
+            .unwrap();
+
+        read_indexed_png(file.to_str().unwrap());
+    }
+
+    #[test]
+    fn takes_the_transparent_color_from_a_given_palette() {
+        let palette = [
+            Color {
+                r: 1,
+                g: 2,
+                b: 3,
+                a: 255,
+            },
+            Color {
+                r: 4,
+                g: 5,
+                b: 6,
+                a: 255,
+            },
+        ];
+
+        // The file isn't read when the palette is already known
+        let color = get_transparent_color(
+            "missing.aseprite",
+            &[palette[1]],
+            Vector2::new(1, 1),
+            TransparentSource::Palette,
+            Some(&palette),
+        );
+
+        assert!(color == palette[0]);
+    }
+}
@@ -1,13 +1,25 @@
-use basic_types::{error_out, Color, Vector2};
+use basic_types::{error_out, Color, FinishedRawData, IndexedData, Section, Vector2};
 use clap::Parser;
 use image::{open, DynamicImage};
 use image_settings::{OutputType, TileSettings};
 
+pub mod aseprite_processor;
+pub mod attribute_processor;
 pub mod basic_types;
 pub mod color_processor;
+pub mod font_processor;
 pub mod image_processor;
 pub mod image_settings;
+pub mod input_processor;
+pub mod map_processor;
+pub mod nes_processor;
 pub mod output_processor;
+pub mod sprite_processor;
+pub mod texture_processor;
+pub mod tiled_processor;
+
+/// The refresh rate of the GBA and NDS screens, which animation frame durations are converted to
+const VIDEO_FRAME_RATE: f64 = 59.73;
 
 /// Read an image and get the raw image data from the image
 fn get_raw_image_data(file: &str) -> (Vec<Color>, Vector2<usize>) {
@@ -32,51 +44,291 @@ fn get_raw_image_data(file: &str) -> (Vec<Color>, Vector2<usize>) {
         y: buffer.height() as usize,
     };
 
-    let buffer = buffer.into_rgb8();
+    let buffer = buffer.into_rgba8();
     let buffer_vec = buffer.as_raw();
 
     let mut raw_data: Vec<Color> = Vec::new();
 
-    for i in 0..buffer_vec.len() / 3 {
-        // Red, Green, Blue
-        let items: (u8, u8, u8) = (
-            buffer_vec[i * 3 + 0],
-            buffer_vec[i * 3 + 1],
-            buffer_vec[i * 3 + 2],
+    for i in 0..buffer_vec.len() / 4 {
+        // Red, Green, Blue, Alpha
+        let items: (u8, u8, u8, u8) = (
+            buffer_vec[i * 4],
+            buffer_vec[i * 4 + 1],
+            buffer_vec[i * 4 + 2],
+            buffer_vec[i * 4 + 3],
         );
 
         raw_data.push(Color {
-            r: items.0 as u8,
-            g: items.1 as u8,
-            b: items.2 as u8,
-            a: 255,
+            r: items.0,
+            g: items.1,
+            b: items.2,
+            a: items.3,
         })
     }
 
     (raw_data, size)
 }
 
+/// Convert the duration of every frame of an animation from milliseconds to video frames, as 16 bit numbers
+fn get_duration_table(durations: &[f64]) -> Vec<u8> {
+    durations
+        .iter()
+        .flat_map(|&duration| {
+            // Every frame is shown for at least one video frame
+            let video_frames = (duration * VIDEO_FRAME_RATE / 1000.0).round().max(1.0);
+
+            (video_frames as u16).to_le_bytes()
+        })
+        .collect()
+}
+
+/// Read an image (or animation), and process it with the settings
+fn process_file(file: &str, settings: &TileSettings) -> FinishedRawData {
+    // Aseprite files have their frames, palette and tags read straight from the file
+    let aseprite = aseprite_processor::read_aseprite(file, settings);
+    let is_aseprite = aseprite.is_some();
+
+    // Animations have all their frames stacked on top of each other, which are then split into frames again
+    let (animation, aseprite_indexed, tags) = match aseprite {
+        Some(aseprite) => (Some(aseprite.animation), aseprite.indexed, aseprite.tags),
+        None if settings.animation => (input_processor::get_animation(file), None, Vec::new()),
+        None => (None, None, Vec::new()),
+    };
+
+    // Get the raw image data
+    let (mut data, mut frame_size) = match animation {
+        Some(ref animation) => (animation.data.clone(), animation.frame_size),
+        None => get_raw_image_data(file),
+    };
+    let frame_count = animation
+        .as_ref()
+        .map_or(1, |animation| animation.durations.len());
+
+    // The frame options need frames, which come from --frame-size or from the frames of the file
+    if settings.frame_size.is_none() && animation.is_none() {
+        let frame_options = [
+            (settings.separate_frames, "--separate-frames"),
+            (settings.frame_table, "--frame-table"),
+            (settings.dedupe_frames, "--dedupe-frames"),
+            (settings.dedupe_flipped_frames, "--dedupe-flipped-frames"),
+            (settings.font, "--font"),
+        ];
+
+        for (used, option) in frame_options {
+            if used {
+                panic!(
+                    "Error: {} needs frames, please use --frame-size (or --animation for animated images)!",
+                    option
+                );
+            }
+        }
+    }
+
+    // The palette of an Aseprite file can be where the transparent color comes from, even when it isn't kept
+    let aseprite_palette = aseprite_indexed
+        .as_ref()
+        .map(|indexed| indexed.palette.clone());
+
+    // Read the palette straight from the file, if we want to keep it as-is
+    let indexed = if animation.is_some() && !is_aseprite && settings.preserve_palette {
+        println!(
+            "Warning: {} is animated, so its palette will be generated instead",
+            file
+        );
+
+        None
+    } else if settings.preserve_palette {
+        let mut indexed = if is_aseprite {
+            aseprite_indexed
+        } else {
+            input_processor::get_indexed_image_data(file)
+        };
+
+        if indexed.is_none() {
+            println!(
+                "Warning: {} is not an indexed image, so its palette will be generated instead",
+                file
+            );
+        }
+
+        // Only use part of the image
+        if let (Some(ref mut indexed), Some((position, size))) = (&mut indexed, settings.crop) {
+            indexed.indices =
+                image_processor::crop_frames(&indexed.indices, frame_size, position, size);
+        }
+
+        indexed
+    } else {
+        None
+    };
+
+    // Only use part of the image
+    if let Some((position, size)) = settings.crop {
+        data = image_processor::crop_frames(&data, frame_size, position, size);
+        frame_size = size;
+    }
+
+    let image_size = Vector2 {
+        x: frame_size.x,
+        y: frame_size.y * frame_count,
+    };
+
+    // Find the transparent color of this image, if it should be detected automatically
+    let transparent_color = match settings.transparent_source {
+        Some(source) => Some(input_processor::get_transparent_color(
+            file,
+            &data,
+            image_size,
+            source,
+            aseprite_palette.as_deref(),
+        )),
+        None => settings.transparent_color,
+    };
+
+    // Pad the image with the transparent color (or palette index 0) so it can be split into tiles
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+    let (data, indexed, frame_size) = if settings.pad_to_tile {
+        let transparent = transparent_color.unwrap_or(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        });
+        let indexed = indexed.map(|indexed| IndexedData {
+            indices: image_processor::pad_frames(&indexed.indices, frame_size, tile_size, 0).0,
+            ..indexed
+        });
+        let (data, padded_size) =
+            image_processor::pad_frames(&data, frame_size, tile_size, transparent);
+
+        (data, indexed, padded_size)
+    } else {
+        (data, indexed, frame_size)
+    };
+
+    let image_size = Vector2 {
+        x: frame_size.x,
+        y: frame_size.y * frame_count,
+    };
+
+    // The settings of this image, which the attribute map uses as well so its tiles are in the same order
+    let image_settings = TileSettings {
+        image_size,
+        transparent_color,
+        // A file with only one frame is a plain image
+        frame_size: settings
+            .frame_size
+            .or((frame_count > 1).then_some(frame_size)),
+        ..settings.clone()
+    };
+
+    // Process the image
+    let mut data = image_processor::process_image(data, indexed, image_settings.clone());
+
+    if let Some(ref animation) = animation {
+        if animation.durations.len() > 1 {
+            data.sections.push(Section {
+                name: "durations".to_string(),
+                data: get_duration_table(&animation.durations),
+            });
+        }
+    }
+
+    data.sections.extend(tags);
+
+    // Give every tile a value from the colors of the attribute image
+    if let Some(ref attribute_image) = settings.attribute_image {
+        let (mut attributes, mut attribute_size) = get_raw_image_data(attribute_image);
+
+        // The attribute image is cropped and padded the same way as the image, so they still line up
+        if let Some((position, size)) = settings.crop {
+            attributes = image_processor::crop_frames(&attributes, attribute_size, position, size);
+            attribute_size = size;
+        }
+
+        if settings.pad_to_tile {
+            (attributes, attribute_size) = image_processor::pad_frames(
+                &attributes,
+                attribute_size,
+                tile_size,
+                Color {
+                    r: 0,
+                    g: 0,
+                    b: 0,
+                    a: 0,
+                },
+            );
+        }
+
+        if attribute_size != image_size {
+            panic!(
+                "Error: the attribute image is {}x{} pixels, but {} is {}x{} pixels!",
+                attribute_size.x, attribute_size.y, file, image_size.x, image_size.y
+            );
+        }
+
+        // The attribute map is in the same order as the tiles, so it leaves out the frames that were deduplicated
+        let frame_index_table = data
+            .sections
+            .iter()
+            .find(|section| section.name == "frameidx")
+            .map(|section| section.data.clone());
+
+        data.sections.push(Section {
+            name: "tileattr".to_string(),
+            data: if settings.include_map_data {
+                attribute_processor::create_attribute_map(
+                    &attributes,
+                    attribute_size,
+                    &image_settings,
+                )
+            } else {
+                attribute_processor::create_frame_attribute_map(
+                    &attributes,
+                    attribute_size,
+                    &image_settings,
+                    frame_index_table.as_deref(),
+                )
+            },
+        });
+    }
+
+    data
+}
+
 fn main() {
     let cli = image_settings::TileCLI::parse();
 
     let settings = TileSettings::from(&cli);
+    settings.validate();
 
     if cli.output_name.is_some() && cli.files.len() > 1 {
         panic!("Output Name cannot be used if more than 1 files is being processed!");
     }
 
+    // Only Tiled maps have layers to use as the attribute image
+    if settings.attribute_layer.is_some() {
+        if let Some(file) = cli
+            .files
+            .iter()
+            .find(|file| !tiled_processor::is_tiled_map(file))
+        {
+            panic!(
+                "Error: {} isn't a Tiled map, so --attribute-layer can't be used with it, please use --attribute-image instead!",
+                file
+            );
+        }
+    }
+
     for file in &cli.files {
-        // Get the raw image data
-        let data = get_raw_image_data(&file);
-
-        // Process the image
-        let data = image_processor::process_image(
-            data.0,
-            TileSettings {
-                image_size: data.1,
-                ..settings.clone()
-            },
-        );
+        // Tiled maps are made from the tiles of their tilesets instead of one image
+        let data = match tiled_processor::read_tiled_map(file, &settings) {
+            Some(data) => data,
+            None => process_file(file, &settings),
+        };
 
         // Output the image
         match cli.output_type {
@@ -91,5 +343,12 @@ fn main() {
                 }
             }
         }
+
+        // Output the palette in the editable formats
+        if let Some(ref name) = cli.output_name {
+            output_processor::to_palette_files(name.as_str(), &data, &settings.palette_exports);
+        } else {
+            output_processor::to_palette_files(file.as_str(), &data, &settings.palette_exports);
+        }
     }
 }
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use crate::basic_types::*;
+use crate::image_settings::*;
+
+/// One entry of a tile map, before it is converted to the format of the map
+#[derive(Clone, Copy, Default)]
+pub struct MapEntry {
+    pub tile: usize,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub palette: u8,
+    pub priority: bool,
+}
+
+/// Flip a tile horizontally and/or vertically
+pub fn flip_tile<T: Clone>(
+    tile: &[T],
+    tile_size: Vector2<usize>,
+    hflip: bool,
+    vflip: bool,
+) -> Vec<T> {
+    let mut flipped: Vec<T> = Vec::with_capacity(tile.len());
+
+    for y in 0..tile_size.y {
+        let y = if vflip { tile_size.y - 1 - y } else { y };
+
+        for x in 0..tile_size.x {
+            let x = if hflip { tile_size.x - 1 - x } else { x };
+
+            flipped.push(tile[y * tile_size.x + x].clone());
+        }
+    }
+
+    flipped
+}
+
+/// Find the unique tiles of the image (depending on settings.map_reduce), and which tile is placed at each position of the map
+pub fn create_map(tiles: &[Vec<u32>], settings: &TileSettings) -> (Vec<Vec<u32>>, Vec<MapEntry>) {
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+
+    if settings.map_reduce == MapReduce::Flipped && !settings.map_format.supports_flipping() {
+        panic!("Error: the selected map format can't flip tiles, so tiles can't be reduced by flipping them!");
+    }
+
+    let mut unique_tiles: Vec<Vec<u32>> = Vec::new();
+    let mut tile_indices: HashMap<Vec<u32>, usize> = HashMap::new();
+    let mut entries: Vec<MapEntry> = Vec::new();
+
+    for tile in tiles {
+        let mut entry = MapEntry {
+            palette: settings.map_palette,
+            priority: settings.map_priority,
+            ..Default::default()
+        };
+
+        let flips: &[(bool, bool)] = match settings.map_reduce {
+            MapReduce::None => &[],
+            MapReduce::Tiles => &[(false, false)],
+            MapReduce::Flipped => &[(false, false), (true, false), (false, true), (true, true)],
+        };
+
+        // Look for a tile we already have, which can be flipped to look like this one
+        let existing = flips.iter().find_map(|&(hflip, vflip)| {
+            tile_indices
+                .get(&flip_tile(tile, tile_size, hflip, vflip))
+                .map(|&index| (index, hflip, vflip))
+        });
+
+        match existing {
+            Some((index, hflip, vflip)) => {
+                entry.tile = index;
+                entry.hflip = hflip;
+                entry.vflip = vflip;
+            }
+            None => {
+                entry.tile = unique_tiles.len();
+                tile_indices.insert(tile.clone(), unique_tiles.len());
+                unique_tiles.push(tile.clone());
+            }
+        }
+
+        entries.push(entry);
+    }
+
+    (unique_tiles, entries)
+}
+
+/// Convert the map entries to the format of the map, and add them (and any extra sections the format needs) to final_data
+pub fn encode_map(entries: &[MapEntry], settings: &TileSettings, final_data: &mut FinishedRawData) {
+    let tile_count = entries
+        .iter()
+        .map(|entry| entry.tile + 1)
+        .max()
+        .unwrap_or(0);
+
+    if tile_count > settings.map_format.get_max_tile_count() {
+        panic!(
+            "Error: the map uses {} tiles, but the selected map format only supports {} tiles!",
+            tile_count,
+            settings.map_format.get_max_tile_count()
+        );
+    }
+
+    match settings.map_format {
+        MapFormat::Gba => {
+            for entry in entries {
+                let value: u16 = entry.tile as u16
+                    | (entry.hflip as u16) << 10
+                    | (entry.vflip as u16) << 11
+                    | ((entry.palette & 0xF) as u16) << 12;
+
+                final_data.map_data.extend(value.to_le_bytes());
+            }
+        }
+        MapFormat::Gb => {
+            final_data.map_data = entries.iter().map(|entry| entry.tile as u8).collect();
+        }
+        MapFormat::Gbc => {
+            final_data.map_data = entries.iter().map(|entry| entry.tile as u8).collect();
+
+            // Tiles past 255 are in the second VRAM bank
+            let attributes = entries
+                .iter()
+                .map(|entry| {
+                    (entry.palette & 0x7)
+                        | ((entry.tile >= 256) as u8) << 3
+                        | (entry.hflip as u8) << 5
+                        | (entry.vflip as u8) << 6
+                        | (entry.priority as u8) << 7
+                })
+                .collect();
+
+            final_data.sections.push(Section {
+                name: "mapattr".to_string(),
+                data: attributes,
+            });
+        }
+        MapFormat::Snes => {
+            for entry in entries {
+                let value: u16 = entry.tile as u16
+                    | ((entry.palette & 0x7) as u16) << 10
+                    | (entry.priority as u16) << 13
+                    | (entry.hflip as u16) << 14
+                    | (entry.vflip as u16) << 15;
+
+                final_data.map_data.extend(value.to_le_bytes());
+            }
+        }
+        MapFormat::Mode7 | MapFormat::Nes => {
+            final_data.map_data = entries.iter().map(|entry| entry.tile as u8).collect();
+        }
+        MapFormat::MegaDrive => {
+            for entry in entries {
+                let value: u16 = entry.tile as u16
+                    | (entry.hflip as u16) << 11
+                    | (entry.vflip as u16) << 12
+                    | ((entry.palette & 0x3) as u16) << 13
+                    | (entry.priority as u16) << 15;
+
+                final_data.map_data.extend(value.to_be_bytes());
+            }
+        }
+        MapFormat::MasterSystem => {
+            for entry in entries {
+                let value: u16 = entry.tile as u16
+                    | (entry.hflip as u16) << 9
+                    | (entry.vflip as u16) << 10
+                    | ((entry.palette & 0x1) as u16) << 11
+                    | (entry.priority as u16) << 12;
+
+                final_data.map_data.extend(value.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Interleave the map and the tiles the way Mode 7 stores them in VRAM: the low byte of each word is from the 128x128 map,
+/// and the high byte is from the 8bpp tiles
+pub fn interleave_mode7(final_data: &mut FinishedRawData, settings: &TileSettings) {
+    const MODE7_MAP_SIZE: usize = 128;
+
+    let map_width = settings.image_size.x / settings.size_per_tile.x as usize;
+    let map_height = settings.image_size.y / settings.size_per_tile.y as usize;
+
+    if map_width > MODE7_MAP_SIZE || map_height > MODE7_MAP_SIZE {
+        panic!("Error: Mode 7 maps can be at most 128x128 tiles!");
+    }
+
+    // Place the map in the top left corner of the 128x128 map
+    let mut map = vec![0u8; MODE7_MAP_SIZE * MODE7_MAP_SIZE];
+
+    for (row, entries) in final_data.map_data.chunks(map_width).enumerate() {
+        map[row * MODE7_MAP_SIZE..row * MODE7_MAP_SIZE + map_width].copy_from_slice(entries);
+    }
+
+    let length = map.len().max(final_data.image_data.len());
+
+    final_data.image_data = (0..length)
+        .flat_map(|i| {
+            [
+                map.get(i).copied().unwrap_or(0),
+                final_data.image_data.get(i).copied().unwrap_or(0),
+            ]
+        })
+        .collect();
+    final_data.map_data.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode two entries: tile 0x123 flipped horizontally with palette 5 and priority, and tile 1 flipped vertically
+    fn encode(map_format: MapFormat) -> FinishedRawData {
+        let entries = [
+            MapEntry {
+                tile: 0x123,
+                hflip: true,
+                vflip: false,
+                palette: 5,
+                priority: true,
+            },
+            MapEntry {
+                tile: 1,
+                vflip: true,
+                ..Default::default()
+            },
+        ];
+        let settings = TileSettings {
+            map_format,
+            ..Default::default()
+        };
+
+        let mut final_data = FinishedRawData::default();
+        encode_map(&entries, &settings, &mut final_data);
+
+        final_data
+    }
+
+    #[test]
+    fn encodes_gba_map_entries() {
+        // ppppvhtttttttttt, little endian
+        assert_eq!(
+            encode(MapFormat::Gba).map_data,
+            vec![0x23, 0x55, 0x01, 0x08]
+        );
+    }
+
+    #[test]
+    fn encodes_gbc_map_entries() {
+        // The attribute byte is pvh0bppp, where b is the VRAM bank of tiles past 255
+        let final_data = encode(MapFormat::Gbc);
+
+        assert_eq!(final_data.map_data, vec![0x23, 0x01]);
+        assert_eq!(final_data.sections[0].name, "mapattr");
+        assert_eq!(final_data.sections[0].data, vec![0xAD, 0x40]);
+    }
+
+    #[test]
+    fn encodes_snes_map_entries() {
+        // vhopppcccccccccc, little endian
+        assert_eq!(
+            encode(MapFormat::Snes).map_data,
+            vec![0x23, 0x75, 0x01, 0x80]
+        );
+    }
+
+    #[test]
+    fn encodes_mega_drive_map_entries() {
+        // pccvhnnnnnnnnnnn, big endian
+        assert_eq!(
+            encode(MapFormat::MegaDrive).map_data,
+            vec![0xA9, 0x23, 0x10, 0x01]
+        );
+    }
+
+    #[test]
+    fn encodes_master_system_map_entries() {
+        // 000pcvhnnnnnnnnn, little endian
+        assert_eq!(
+            encode(MapFormat::MasterSystem).map_data,
+            vec![0x23, 0x1B, 0x01, 0x04]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+
+use crate::basic_types::*;
+use crate::image_settings::*;
+
+/// The size of the area that shares one palette in the attribute table, in pixels
+const ATTRIBUTE_AREA_SIZE: usize = 16;
+/// How many sub-palettes there are
+const PALETTE_COUNT: usize = 4;
+/// How many colors each sub-palette has, not counting the shared background color
+const COLORS_PER_PALETTE: usize = 3;
+/// How many combinations of palettes to try before giving up
+const MAX_MERGE_STEPS: usize = 100_000;
+
+/// Split the colors of the image into four 3 color palettes with a shared background color, so that each 16x16 area only uses
+/// one of them. Returns the 16 color palette, the index of every pixel within its palette, and the attribute table
+pub fn get_nes_palette(data: &[Color], settings: &TileSettings) -> (Vec<Color>, Vec<u32>, Vec<u8>) {
+    if settings.size_per_tile != Vector2::new(8, 8) {
+        panic!("Error: NES tiles are always 8x8 pixels!");
+    }
+
+    let size = settings.image_size;
+    let areas = Vector2::new(
+        size.x.div_ceil(ATTRIBUTE_AREA_SIZE),
+        size.y.div_ceil(ATTRIBUTE_AREA_SIZE),
+    );
+
+    // The background color is shared by every palette. It is the most used color, and ties go to the color that appears
+    // first
+    let background = settings.transparent_color.unwrap_or_else(|| {
+        let counts = data.iter().counts();
+
+        data.iter()
+            .unique()
+            .rev()
+            .max_by_key(|color| counts[color])
+            .copied()
+            .unwrap()
+    });
+
+    // Find the colors every area uses
+    let mut area_colors: Vec<BTreeSet<Color>> = vec![BTreeSet::new(); areas.x * areas.y];
+
+    for (i, &color) in data.iter().enumerate() {
+        let area =
+            (i / size.x / ATTRIBUTE_AREA_SIZE) * areas.x + (i % size.x) / ATTRIBUTE_AREA_SIZE;
+
+        if color != background {
+            area_colors[area].insert(color);
+        }
+    }
+
+    let mut report: Vec<String> = Vec::new();
+
+    for (area, colors) in area_colors.iter().enumerate() {
+        if colors.len() > COLORS_PER_PALETTE {
+            report.push(format!(
+                "the 16x16 area at ({}, {}) uses {} colors besides the background color",
+                (area % areas.x) * ATTRIBUTE_AREA_SIZE,
+                (area / areas.x) * ATTRIBUTE_AREA_SIZE,
+                colors.len()
+            ));
+        }
+    }
+
+    // Merge the colors of the areas into palettes, trying other ways of combining them when the first one doesn't fit
+    let mut palettes: Vec<BTreeSet<Color>> = Vec::new();
+
+    if report.is_empty() {
+        let color_sets = get_color_sets(&area_colors);
+        let mut steps = 0;
+
+        if !merge_color_sets(&color_sets, &mut palettes, &mut steps) {
+            report.push(format!(
+                "the colors of the areas ({}) can't be split into 4 palettes of 3 colors",
+                color_sets
+                    .iter()
+                    .map(|colors| {
+                        colors
+                            .iter()
+                            .map(|color| format!("#{:02X}{:02X}{:02X}", color.r, color.g, color.b))
+                            .join(" ")
+                    })
+                    .join(", ")
+            ));
+        }
+    }
+
+    if !report.is_empty() {
+        panic!(
+            "Error: the image doesn't fit the NES palette limits:\n{}",
+            report.join("\n")
+        );
+    }
+
+    // An image that only has the background color still needs a palette
+    if palettes.is_empty() {
+        palettes.push(BTreeSet::new());
+    }
+
+    let palettes: Vec<Vec<Color>> = palettes
+        .into_iter()
+        .map(|palette| palette.into_iter().collect())
+        .collect();
+
+    // Every area uses the first palette that has all of its colors
+    let area_palettes: Vec<usize> = area_colors
+        .iter()
+        .map(|colors| {
+            palettes
+                .iter()
+                .position(|palette| colors.iter().all(|color| palette.contains(color)))
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let indices: Vec<u32> = data
+        .iter()
+        .enumerate()
+        .map(|(i, color)| {
+            let area =
+                (i / size.x / ATTRIBUTE_AREA_SIZE) * areas.x + (i % size.x) / ATTRIBUTE_AREA_SIZE;
+
+            match palettes[area_palettes[area]]
+                .iter()
+                .position(|entry| entry == color)
+            {
+                Some(index) => index as u32 + 1,
+                None => 0,
+            }
+        })
+        .collect();
+
+    // Each sub-palette starts with the background color, and unused entries are the background color as well
+    let mut palette: Vec<Color> = Vec::new();
+
+    for i in 0..PALETTE_COUNT {
+        let colors = palettes.get(i).cloned().unwrap_or_default();
+
+        palette.push(background);
+
+        for j in 0..COLORS_PER_PALETTE {
+            palette.push(colors.get(j).copied().unwrap_or(background));
+        }
+    }
+
+    (
+        palette,
+        indices,
+        create_attribute_table(&area_palettes, areas),
+    )
+}
+
+/// The different sets of colors the areas use, largest first, leaving out sets that are part of a larger one (since they
+/// can always use the same palette)
+fn get_color_sets(area_colors: &[BTreeSet<Color>]) -> Vec<BTreeSet<Color>> {
+    let mut color_sets: Vec<BTreeSet<Color>> = Vec::new();
+
+    for colors in area_colors
+        .iter()
+        .unique()
+        .sorted_by_key(|colors| std::cmp::Reverse(colors.len()))
+    {
+        if !colors.is_empty() && !color_sets.iter().any(|set| set.is_superset(colors)) {
+            color_sets.push(colors.clone());
+        }
+    }
+
+    color_sets
+}
+
+/// Put every set of colors into a palette, trying the palettes that already have the most of its colors first, and going
+/// back to try the other palettes when the remaining sets don't fit. Returns false if there is no way to fit them (or
+/// it takes too long to find one)
+fn merge_color_sets(
+    color_sets: &[BTreeSet<Color>],
+    palettes: &mut Vec<BTreeSet<Color>>,
+    steps: &mut usize,
+) -> bool {
+    let Some((colors, rest)) = color_sets.split_first() else {
+        return true;
+    };
+
+    *steps += 1;
+    if *steps > MAX_MERGE_STEPS {
+        return false;
+    }
+
+    let candidates: Vec<usize> = palettes
+        .iter()
+        .positions(|palette| palette.union(colors).count() <= COLORS_PER_PALETTE)
+        .sorted_by_key(|&i| std::cmp::Reverse(palettes[i].intersection(colors).count()))
+        .collect();
+
+    for i in candidates {
+        let previous = palettes[i].clone();
+        palettes[i].extend(colors.iter().copied());
+
+        if merge_color_sets(rest, palettes, steps) {
+            return true;
+        }
+
+        palettes[i] = previous;
+    }
+
+    if palettes.len() < PALETTE_COUNT {
+        palettes.push(colors.clone());
+
+        if merge_color_sets(rest, palettes, steps) {
+            return true;
+        }
+
+        palettes.pop();
+    }
+
+    false
+}
+
+/// Pack the palette of every area into the attribute table, where each byte covers 2x2 areas
+fn create_attribute_table(area_palettes: &[usize], areas: Vector2<usize>) -> Vec<u8> {
+    let mut table: Vec<u8> = Vec::new();
+    let area_palette = |x: usize, y: usize| -> u8 {
+        if x < areas.x && y < areas.y {
+            area_palettes[y * areas.x + x] as u8
+        } else {
+            0
+        }
+    };
+
+    for y in (0..areas.y).step_by(2) {
+        for x in (0..areas.x).step_by(2) {
+            table.push(
+                area_palette(x, y)
+                    | area_palette(x + 1, y) << 2
+                    | area_palette(x, y + 1) << 4
+                    | area_palette(x + 1, y + 1) << 6,
+            );
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor;
+
+    fn color(value: u8) -> Color {
+        Color {
+            r: value * 20 + 10,
+            g: 0,
+            b: 0,
+            a: 255,
+        }
+    }
+
+    /// Make an image one 16x16 area high, where each area uses the given colors on a black background
+    fn build_image(areas: &[&[u8]]) -> (Vec<Color>, TileSettings) {
+        let size = Vector2::new(areas.len() * ATTRIBUTE_AREA_SIZE, ATTRIBUTE_AREA_SIZE);
+        let mut data = vec![
+            Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255
+            };
+            size.x * size.y
+        ];
+
+        for (area, colors) in areas.iter().enumerate() {
+            for (i, &value) in colors.iter().enumerate() {
+                data[area * ATTRIBUTE_AREA_SIZE + i] = color(value);
+            }
+        }
+
+        let settings = TileSettings {
+            bpp: BitsPerPixel::Bpp2,
+            tile_format: TileFormat::Nes,
+            transparent_color: None,
+            image_size: size,
+            ..Default::default()
+        };
+
+        (data, settings)
+    }
+
+    #[test]
+    fn fits_areas_that_need_a_different_merge_order() {
+        // Merging [4, 7] into the palette of [6, 7] first leaves no room for the other areas
+        let areas: &[&[u8]] = &[
+            &[6, 7],
+            &[4, 7],
+            &[4, 8],
+            &[7, 8],
+            &[1, 4],
+            &[0, 1, 8],
+            &[2, 4, 8],
+        ];
+        let (data, settings) = build_image(areas);
+        let (palette, indices, _) = get_nes_palette(&data, &settings);
+
+        assert_eq!(palette.len(), 16);
+
+        // Every pixel still has its own color
+        for (area, colors) in areas.iter().enumerate() {
+            let palette_index = palette
+                .chunks(4)
+                .position(|sub| colors.iter().all(|&value| sub.contains(&color(value))))
+                .unwrap();
+
+            for (i, &value) in colors.iter().enumerate() {
+                let index = indices[area * ATTRIBUTE_AREA_SIZE + i] as usize;
+
+                assert_eq!(palette[palette_index * 4 + index], color(value));
+            }
+        }
+    }
+
+    #[test]
+    fn picks_the_first_of_tied_background_colors() {
+        // Four colors, each filling a quarter of the image
+        let (_, settings) = build_image(&[&[]]);
+        let data: Vec<Color> = (0..ATTRIBUTE_AREA_SIZE * ATTRIBUTE_AREA_SIZE)
+            .map(|i| color([3, 1, 2, 0][i * 4 / (ATTRIBUTE_AREA_SIZE * ATTRIBUTE_AREA_SIZE)]))
+            .collect();
+
+        for _ in 0..8 {
+            let (palette, indices, _) = get_nes_palette(&data, &settings);
+
+            assert_eq!(palette[0], color(3));
+            assert_eq!(indices[0], 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be split into 4 palettes")]
+    fn rejects_too_many_colors() {
+        let (data, settings) =
+            build_image(&[&[0, 1, 2], &[3, 4, 5], &[6, 7, 8], &[9, 10, 11], &[0, 3, 6]]);
+
+        get_nes_palette(&data, &settings);
+    }
+
+    #[test]
+    fn packs_attribute_table() {
+        // 3x3 areas, the areas past the right and bottom use palette 0
+        let table = create_attribute_table(&[1, 2, 3, 0, 1, 2, 3, 0, 1], Vector2::new(3, 3));
+
+        assert_eq!(
+            table,
+            vec![0b01_00_10_01, 0b00_10_00_11, 0b00_00_00_11, 0b00_00_00_01]
+        );
+    }
+
+    #[test]
+    fn encodes_chr_tiles() {
+        let settings = TileSettings {
+            bpp: BitsPerPixel::Bpp2,
+            tile_format: TileFormat::Nes,
+            ..Default::default()
+        };
+
+        // The first row is 0 1 2 3 0 1 2 3, the other rows are all 3
+        let mut tile: Vec<u32> = vec![0, 1, 2, 3, 0, 1, 2, 3];
+        tile.extend([3; 56]);
+
+        let chr = image_processor::process_tile_paletted(&tile, &settings);
+
+        assert_eq!(chr.len(), 16);
+        assert_eq!(chr[0], 0b0101_0101);
+        assert_eq!(chr[8], 0b0011_0011);
+        assert!(chr[1..8]
+            .iter()
+            .chain(&chr[9..16])
+            .all(|&byte| byte == 0xFF));
+    }
+}
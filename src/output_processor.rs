@@ -3,14 +3,17 @@ use std::{
     io::Write,
 };
 
+use image::{Rgba, RgbaImage};
+
 use crate::basic_types::*;
+use crate::image_settings::PaletteExport;
 
 // Write the given data to the files
 pub fn to_raw_binary_files(filename_prefix: &str, data: &FinishedRawData) {
     let mut img_file: File = OpenOptions::new()
         .create(true)
         .write(true)
-        .append(false)
+        .truncate(true)
         .open(filename_prefix.to_string() + ".img.bin")
         .unwrap();
 
@@ -27,7 +30,7 @@ pub fn to_raw_binary_files(filename_prefix: &str, data: &FinishedRawData) {
         let mut pal_file: File = OpenOptions::new()
             .create(true)
             .write(true)
-            .append(false)
+            .truncate(true)
             .open(filename_prefix.to_string() + ".pal.bin")
             .unwrap();
 
@@ -40,4 +43,112 @@ pub fn to_raw_binary_files(filename_prefix: &str, data: &FinishedRawData) {
             }
         }
     }
+    if !data.map_data.is_empty() {
+        write_binary_file(&(filename_prefix.to_string() + ".map.bin"), &data.map_data);
+    }
+
+    for section in &data.sections {
+        write_binary_file(
+            &format!("{}.{}.bin", filename_prefix, section.name),
+            &section.data,
+        );
+    }
+}
+
+/// Write the palette in the given editable formats, so it can be inspected or edited in other programs
+pub fn to_palette_files(filename_prefix: &str, data: &FinishedRawData, exports: &[PaletteExport]) {
+    if data.palette.is_empty() {
+        return;
+    }
+
+    for export in exports {
+        match export {
+            PaletteExport::Jasc => {
+                let mut text = format!("JASC-PAL\r\n0100\r\n{}\r\n", data.palette.len());
+
+                for color in &data.palette {
+                    text += &format!("{} {} {}\r\n", color.r, color.g, color.b);
+                }
+
+                write_text_file(&(filename_prefix.to_string() + ".pal"), &text);
+            }
+            PaletteExport::Gpl => {
+                let mut text = format!("GIMP Palette\nName: {}\nColumns: 16\n#\n", filename_prefix);
+
+                for (i, color) in data.palette.iter().enumerate() {
+                    text += &format!("{:3} {:3} {:3}\tIndex {}\n", color.r, color.g, color.b, i);
+                }
+
+                write_text_file(&(filename_prefix.to_string() + ".gpl"), &text);
+            }
+            PaletteExport::Png => {
+                let filename = filename_prefix.to_string() + ".pal.png";
+
+                match to_swatch_image(&data.palette).save(&filename) {
+                    Ok(_) => {
+                        println!("Done writing file {}", filename);
+                    }
+                    Err(_) => {
+                        error_out(format!("Error: Unable to write {}", filename).as_str());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draw every color of the palette as a square, 16 colors per row
+fn to_swatch_image(palette: &[Color]) -> RgbaImage {
+    const SWATCH_SIZE: u32 = 16;
+    const SWATCHES_PER_ROW: u32 = 16;
+
+    let rows = (palette.len() as u32).div_ceil(SWATCHES_PER_ROW);
+
+    RgbaImage::from_fn(
+        SWATCH_SIZE * SWATCHES_PER_ROW,
+        SWATCH_SIZE * rows,
+        |x, y| {
+            let index = (y / SWATCH_SIZE * SWATCHES_PER_ROW + x / SWATCH_SIZE) as usize;
+
+            match palette.get(index) {
+                Some(color) => Rgba([color.r, color.g, color.b, 255]),
+                None => Rgba([0, 0, 0, 0]),
+            }
+        },
+    )
+}
+
+/// Replace the characters of a name that can't be used in file names
+pub fn get_file_safe_name(name: &str) -> String {
+    name.chars()
+        .map(|character| {
+            if character.is_alphanumeric() || character == '-' || character == '_' {
+                character
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn write_text_file(filename: &str, text: &str) {
+    match std::fs::write(filename, text) {
+        Ok(_) => {
+            println!("Done writing file {}", filename);
+        }
+        Err(_) => {
+            error_out(format!("Error: Unable to write {}", filename).as_str());
+        }
+    }
+}
+
+fn write_binary_file(filename: &str, data: &[u8]) {
+    match std::fs::write(filename, data) {
+        Ok(_) => {
+            println!("Done writing file {}", filename);
+        }
+        Err(_) => {
+            error_out(format!("Error: Unable to write {}", filename).as_str());
+        }
+    }
 }
@@ -0,0 +1,85 @@
+use crate::basic_types::*;
+use crate::image_settings::*;
+
+/// The sizes (in pixels) that a GBA or NDS sprite can be
+const OAM_SIZES: [(usize, usize); 12] = [
+    // Square
+    (8, 8),
+    (16, 16),
+    (32, 32),
+    (64, 64),
+    // Wide
+    (16, 8),
+    (32, 8),
+    (32, 16),
+    (64, 32),
+    // Tall
+    (8, 16),
+    (8, 32),
+    (16, 32),
+    (32, 64),
+];
+
+/// How many 4bpp tiles wide the character block is with 2D mapping
+const CHARBLOCK_WIDTH: usize = 32;
+/// How many 4bpp tiles fit in the sprite VRAM
+const CHARBLOCK_TILES: usize = 1024;
+
+/// Make sure every frame is the size of a sprite
+pub fn check_sprite_size(settings: &TileSettings) {
+    let size = settings.frame_size.unwrap_or(settings.image_size);
+
+    if !OAM_SIZES.contains(&(size.x, size.y)) {
+        panic!(
+            "Error: sprites can't be {}x{} pixels! The sizes sprites can be are 8x8, 16x16, 32x32, 64x64 (square), 16x8, 32x8, 32x16, 64x32 (wide), and 8x16, 8x32, 16x32, 32x64 (tall)",
+            size.x, size.y
+        );
+    }
+}
+
+/// Place the tiles of every frame in a character block with 2D mapping, where the rows of tiles of a sprite are
+/// 32 tiles apart (16 for 8bpp, since those tiles take up the space of two). The frames are placed left to right, then
+/// top to bottom. Returns the character block, and the offset of every frame in it
+pub fn place_sprites_2d(frames: &[Vec<u8>], settings: &TileSettings) -> (Vec<u8>, Vec<u32>) {
+    let frame_size = settings.frame_size.unwrap_or(settings.image_size);
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+    let tile_length = tile_size.x * tile_size.y * settings.bpp.to_num() / 8;
+
+    // How many tiles wide the character block and each frame are
+    let width = CHARBLOCK_WIDTH * 4 / settings.bpp.to_num();
+    let frame_tiles = Vector2 {
+        x: frame_size.x / tile_size.x,
+        y: frame_size.y / tile_size.y,
+    };
+
+    let frames_per_row = width / frame_tiles.x;
+    let rows = frames.len().div_ceil(frames_per_row) * frame_tiles.y;
+
+    let mut charblock = vec![0u8; rows * width * tile_length];
+    let mut offsets: Vec<u32> = Vec::new();
+
+    for (i, frame) in frames.iter().enumerate() {
+        let position = Vector2 {
+            x: (i % frames_per_row) * frame_tiles.x,
+            y: (i / frames_per_row) * frame_tiles.y,
+        };
+
+        offsets.push(((position.y * width + position.x) * tile_length) as u32);
+
+        // Copy every row of tiles of the frame to its place in the character block
+        for (row, tiles) in frame.chunks(frame_tiles.x * tile_length).enumerate() {
+            let start = ((position.y + row) * width + position.x) * tile_length;
+
+            charblock[start..start + tiles.len()].copy_from_slice(tiles);
+        }
+    }
+
+    if charblock.len() > CHARBLOCK_TILES * 32 {
+        println!("Warning: the sprites take up more space than the sprite VRAM has");
+    }
+
+    (charblock, offsets)
+}
@@ -0,0 +1,488 @@
+use itertools::Itertools;
+
+use crate::basic_types::*;
+use crate::color_processor;
+use crate::image_processor;
+use crate::image_settings::*;
+
+/// The smallest and largest width or height of a texture
+const MIN_TEXTURE_SIZE: usize = 8;
+const MAX_TEXTURE_SIZE: usize = 1024;
+/// The width and height of the blocks of compressed textures
+const BLOCK_SIZE: usize = 4;
+/// How many palette entries the palette offset of a compressed block counts in
+const PALETTE_OFFSET_STEP: usize = 2;
+/// The largest palette offset of a compressed block
+const MAX_PALETTE_OFFSET: usize = 0x3FFF;
+
+// The modes of compressed blocks, which decide what each of the 4 possible texel values mean
+/// 3 colors, and transparent
+const MODE_TRANSPARENT: u16 = 0;
+/// 2 colors, their average, and transparent
+const MODE_TRANSPARENT_AVERAGE: u16 = 1;
+/// 4 colors
+const MODE_OPAQUE: u16 = 2;
+/// 2 colors, and two blends of them (5:3 and 3:5)
+const MODE_OPAQUE_BLEND: u16 = 3;
+
+/// Convert the image to an NDS texture of the given format, and add it to final_data
+pub fn process_texture(
+    data: Vec<Color>,
+    indexed: Option<IndexedData>,
+    format: TextureFormat,
+    settings: &TileSettings,
+    final_data: &mut FinishedRawData,
+) {
+    let size = settings.image_size;
+
+    for length in [size.x, size.y] {
+        if !length.is_power_of_two() || !(MIN_TEXTURE_SIZE..=MAX_TEXTURE_SIZE).contains(&length) {
+            panic!("Error: textures must be 8, 16, 32, 64, 128, 256, 512 or 1024 texels wide and tall, but the image is {}x{}!", size.x, size.y);
+        }
+    }
+
+    if indexed.is_some()
+        && !matches!(
+            format,
+            TextureFormat::Palette4 | TextureFormat::Palette16 | TextureFormat::Palette256
+        )
+    {
+        println!("Warning: only the 4, 16 and 256 color texture formats can preserve the palette of an indexed image");
+    }
+
+    let palette = match format {
+        TextureFormat::Palette4 | TextureFormat::Palette16 | TextureFormat::Palette256 => {
+            let bpp = match format {
+                TextureFormat::Palette4 => BitsPerPixel::Bpp2,
+                TextureFormat::Palette16 => BitsPerPixel::Bpp4,
+                _ => BitsPerPixel::Bpp8,
+            };
+
+            // Texels are packed starting from the lowest bits of each byte
+            let settings = TileSettings {
+                bpp,
+                bit_order: BitOrder::LsbFirst,
+                ..settings.clone()
+            };
+
+            let (mut palette, indices) = get_texture_palette(data, indexed, &settings);
+            image_processor::pad_palette(&mut palette, &settings);
+
+            let indices: Vec<u8> = indices.into_iter().map(|index| index as u8).collect();
+            final_data.image_data = image_processor::pack_tile(&indices, &settings);
+
+            palette
+        }
+        TextureFormat::A3i5 => encode_translucent(&data, 5, settings, final_data),
+        TextureFormat::A5i3 => encode_translucent(&data, 3, settings, final_data),
+        TextureFormat::Direct => {
+            for mut color in data {
+                // The transparent color doesn't get the alpha bit
+                if Some(color) == settings.transparent_color {
+                    color.a = 0;
+                }
+
+                final_data
+                    .image_data
+                    .append(&mut color_processor::encode_color(
+                        &color,
+                        &settings.get_direct_color_encoder(),
+                    ));
+            }
+
+            Vec::new()
+        }
+        TextureFormat::Compressed => compress_texture(&data, settings, final_data),
+    };
+
+    final_data.palette_data =
+        color_processor::encode_palette(&palette, &settings.get_palette_encoder());
+
+    final_data.palette = palette;
+}
+
+/// Get the palette and the palette index of every texel of a 4, 16 or 256 color texture
+fn get_texture_palette(
+    data: Vec<Color>,
+    indexed: Option<IndexedData>,
+    settings: &TileSettings,
+) -> (Vec<Color>, Vec<u32>) {
+    if let Some(indexed) = indexed {
+        return image_processor::get_indexed_palette(indexed, settings);
+    }
+
+    let (data, mut settings) = image_processor::apply_transparency(data, settings.clone());
+
+    // Index 0 is drawn as transparent, so no other color can use it
+    if settings.palette0_transparent && settings.transparent_color.is_none() {
+        settings.transparent_color = Some(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        });
+    }
+
+    let palette = image_processor::get_image_palette(&data, &settings);
+    let indices = image_processor::convert_to_paletted(&data, &settings, &palette);
+
+    (palette, indices)
+}
+
+/// Store every texel as a palette index in the low bits, and its alpha in the remaining high bits (A3I5 and A5I3)
+fn encode_translucent(
+    data: &[Color],
+    index_bits: u32,
+    settings: &TileSettings,
+    final_data: &mut FinishedRawData,
+) -> Vec<Color> {
+    let max_palette_length = 1 << index_bits;
+
+    // The palette only has the colors, the alpha is stored in each texel
+    let colors: Vec<Color> = data
+        .iter()
+        .filter(|&&color| color.a != 0 && Some(color) != settings.transparent_color)
+        .map(|&color| Color { a: 255, ..color })
+        .collect();
+
+    let mut palette: Vec<Color> = colors.iter().copied().unique().collect();
+    image_processor::sort_palette(
+        &mut palette,
+        &colors,
+        &TileSettings {
+            transparent_color: None,
+            ..settings.clone()
+        },
+    );
+
+    if palette.len() > max_palette_length {
+        panic!(
+            "Error: the texture has {} colors, but the selected texture format only supports {} colors!",
+            palette.len(),
+            max_palette_length
+        );
+    }
+
+    for &color in data {
+        let texel = if color.a == 0 || Some(color) == settings.transparent_color {
+            0
+        } else {
+            let index = palette
+                .iter()
+                .position(|&entry| entry == Color { a: 255, ..color })
+                .unwrap() as u8;
+            let alpha = color_processor::bit8_to_bits(
+                color.a as u16,
+                8 - index_bits,
+                settings.channel_conversion,
+            ) as u8;
+
+            index | (alpha << index_bits)
+        };
+
+        final_data.image_data.push(texel);
+    }
+
+    palette
+}
+
+/// Split the texture into 4x4 blocks that each use 2 or 4 colors of the palette. The 2 bit texels of every block go in
+/// the image data, and the palette offset and mode of every block go in the "pidx" section
+fn compress_texture(
+    data: &Vec<Color>,
+    settings: &TileSettings,
+    final_data: &mut FinishedRawData,
+) -> Vec<Color> {
+    let blocks = image_processor::tile(
+        data,
+        settings.image_size,
+        Vector2::new(BLOCK_SIZE, BLOCK_SIZE),
+    );
+
+    let mut palette: Vec<Color> = Vec::new();
+    let mut palette_indices: Vec<u8> = Vec::new();
+
+    for block in blocks {
+        let transparent: Vec<bool> = block
+            .iter()
+            .map(|&color| color.a == 0 || Some(color) == settings.transparent_color)
+            .collect();
+        let has_transparent = transparent.contains(&true);
+
+        let colors: Vec<Color> = block
+            .iter()
+            .zip(&transparent)
+            .filter(|&(_, &transparent)| !transparent)
+            .map(|(&color, _)| Color { a: 255, ..color })
+            .unique()
+            .collect();
+
+        // Use the exact colors if they fit, and blend between the two most different colors otherwise
+        let (mode, mut block_palette) = match (has_transparent, colors.len()) {
+            (true, 0..=2) => (MODE_TRANSPARENT_AVERAGE, colors.clone()),
+            (true, 3) => (MODE_TRANSPARENT, colors.clone()),
+            (true, _) => (MODE_TRANSPARENT_AVERAGE, get_endpoints(&colors)),
+            (false, 0..=2) => (MODE_OPAQUE_BLEND, colors.clone()),
+            (false, 3..=4) => (MODE_OPAQUE, colors.clone()),
+            (false, _) => (MODE_OPAQUE_BLEND, get_endpoints(&colors)),
+        };
+
+        let palette_length = match mode {
+            MODE_TRANSPARENT | MODE_OPAQUE => 4,
+            _ => 2,
+        };
+        let padding = block_palette.last().copied().unwrap_or(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        });
+        block_palette.resize(palette_length, padding);
+
+        // The colors each texel value stands for
+        let choices: Vec<Color> = match mode {
+            MODE_TRANSPARENT => block_palette[..3].to_vec(),
+            MODE_TRANSPARENT_AVERAGE => vec![
+                block_palette[0],
+                block_palette[1],
+                blend(block_palette[0], block_palette[1], 4),
+            ],
+            MODE_OPAQUE => block_palette.clone(),
+            _ => vec![
+                block_palette[0],
+                block_palette[1],
+                blend(block_palette[0], block_palette[1], 5),
+                blend(block_palette[0], block_palette[1], 3),
+            ],
+        };
+
+        let mut texels: u32 = 0;
+
+        for (i, (&color, &transparent)) in block.iter().zip(&transparent).enumerate() {
+            let value = if transparent {
+                3
+            } else {
+                let color = Color { a: 255, ..color };
+
+                (0..choices.len())
+                    .min_by_key(|&choice| get_distance(&choices[choice], &color))
+                    .unwrap() as u32
+            };
+
+            texels |= value << (i * 2);
+        }
+
+        final_data.image_data.extend(texels.to_le_bytes());
+
+        // Share the colors with an earlier block if it used the same ones
+        let offset = (0..palette.len())
+            .step_by(PALETTE_OFFSET_STEP)
+            .find(|&i| palette[i..].starts_with(&block_palette))
+            .unwrap_or_else(|| {
+                palette.extend(&block_palette);
+                palette.len() - block_palette.len()
+            })
+            / PALETTE_OFFSET_STEP;
+
+        if offset > MAX_PALETTE_OFFSET {
+            panic!("Error: the compressed texture uses too many different colors for its palette!");
+        }
+
+        palette_indices.extend((offset as u16 | mode << 14).to_le_bytes());
+    }
+
+    final_data.sections.push(Section {
+        name: "pidx".to_string(),
+        data: palette_indices,
+    });
+
+    palette
+}
+
+/// The two colors that are the most different from each other
+fn get_endpoints(colors: &[Color]) -> Vec<Color> {
+    let (first, second) = colors
+        .iter()
+        .tuple_combinations()
+        .max_by_key(|&(a, b)| get_distance(a, b))
+        .unwrap();
+
+    vec![*first, *second]
+}
+
+/// Blend two colors, with weight eighths of the first color
+fn blend(first: Color, second: Color, weight: u16) -> Color {
+    let channel = |a: u8, b: u8| ((a as u16 * weight + b as u16 * (8 - weight)) / 8) as u8;
+
+    Color {
+        r: channel(first.r, second.r),
+        g: channel(first.g, second.g),
+        b: channel(first.b, second.b),
+        a: 255,
+    }
+}
+
+/// The squared distance between two colors
+fn get_distance(a: &Color, b: &Color) -> i32 {
+    (a.r as i32 - b.r as i32).pow(2)
+        + (a.g as i32 - b.g as i32).pow(2)
+        + (a.b as i32 - b.b as i32).pow(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = Color {
+        r: 255,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    const GREEN: Color = Color {
+        r: 0,
+        g: 255,
+        b: 0,
+        a: 255,
+    };
+    const BLUE: Color = Color {
+        r: 0,
+        g: 0,
+        b: 255,
+        a: 255,
+    };
+    const CLEAR: Color = Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    };
+
+    /// Turn a compressed texture back into colors, with None for transparent texels
+    fn decompress(final_data: &FinishedRawData, palette: &[Color]) -> Vec<Vec<Option<Color>>> {
+        let palette_indices = &final_data.sections[0].data;
+
+        final_data
+            .image_data
+            .chunks(4)
+            .zip(palette_indices.chunks(2))
+            .map(|(texels, palette_index)| {
+                let texels = u32::from_le_bytes(texels.try_into().unwrap());
+                let palette_index = u16::from_le_bytes(palette_index.try_into().unwrap());
+                let colors = &palette[(palette_index & 0x3FFF) as usize * PALETTE_OFFSET_STEP..];
+                let mode = palette_index >> 14;
+
+                (0..16)
+                    .map(|i| match ((texels >> (i * 2)) & 3, mode) {
+                        (3, MODE_TRANSPARENT | MODE_TRANSPARENT_AVERAGE) => None,
+                        (value, _) => Some(colors[value as usize]),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compresses_blocks_without_losing_colors() {
+        // Two colors, three colors and transparent, only transparent, and the first block again
+        let blocks: Vec<Vec<Color>> = vec![
+            (0..16).map(|i| [RED, BLUE][i % 2]).collect(),
+            (0..16)
+                .map(|i| {
+                    if i == 0 {
+                        CLEAR
+                    } else {
+                        [RED, GREEN, BLUE][i % 3]
+                    }
+                })
+                .collect(),
+            vec![CLEAR; 16],
+            (0..16).map(|i| [RED, BLUE][i % 2]).collect(),
+        ];
+
+        // Put the blocks in an 8x8 image, two blocks per row
+        let mut data = vec![CLEAR; 64];
+        for (block, colors) in blocks.iter().enumerate() {
+            for (i, &color) in colors.iter().enumerate() {
+                let x = (block % 2) * BLOCK_SIZE + i % BLOCK_SIZE;
+                let y = (block / 2) * BLOCK_SIZE + i / BLOCK_SIZE;
+                data[y * 8 + x] = color;
+            }
+        }
+
+        let settings = TileSettings {
+            image_size: Vector2::new(8, 8),
+            transparent_color: None,
+            ..Default::default()
+        };
+        let mut final_data = FinishedRawData::default();
+        let palette = compress_texture(&data, &settings, &mut final_data);
+
+        assert_eq!(final_data.image_data.len(), 16);
+
+        let palette_indices: Vec<u16> = final_data.sections[0]
+            .data
+            .chunks(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        assert_eq!(
+            palette_indices
+                .iter()
+                .map(|index| index >> 14)
+                .collect_vec(),
+            vec![
+                MODE_OPAQUE_BLEND,
+                MODE_TRANSPARENT,
+                MODE_TRANSPARENT_AVERAGE,
+                MODE_OPAQUE_BLEND
+            ]
+        );
+        // The last block shares the colors of the first one
+        assert_eq!(palette_indices[0], palette_indices[3]);
+
+        for (decompressed, colors) in decompress(&final_data, &palette).iter().zip(&blocks) {
+            let expected: Vec<Option<Color>> = colors
+                .iter()
+                .map(|&color| (color.a != 0).then_some(color))
+                .collect();
+
+            assert_eq!(decompressed, &expected);
+        }
+    }
+
+    #[test]
+    fn blends_blocks_with_too_many_colors() {
+        let black = Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        let white = Color {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        let gray = Color {
+            r: 128,
+            g: 128,
+            b: 128,
+            a: 255,
+        };
+
+        let data: Vec<Color> = (0..16)
+            .map(|i| [black, white, gray, RED, GREEN][i % 5])
+            .collect();
+        let settings = TileSettings {
+            image_size: Vector2::new(4, 4),
+            transparent_color: None,
+            ..Default::default()
+        };
+        let mut final_data = FinishedRawData::default();
+        let palette = compress_texture(&data, &settings, &mut final_data);
+
+        assert_eq!(palette.len(), 2);
+        assert_eq!(final_data.sections[0].data, vec![0x00, 0xC0]);
+    }
+}
@@ -0,0 +1,951 @@
+use std::{collections::HashMap, fs, io::Read, path::Path};
+
+use base64::Engine;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::Value;
+
+use crate::attribute_processor;
+use crate::basic_types::*;
+use crate::color_processor;
+use crate::image_processor;
+use crate::image_settings::*;
+use crate::map_processor::{self, MapEntry};
+use crate::output_processor;
+
+/// The flags in the highest bits of every tile of a layer
+const FLIPPED_HORIZONTALLY: u32 = 0x80000000;
+const FLIPPED_VERTICALLY: u32 = 0x40000000;
+const FLIPPED_DIAGONALLY: u32 = 0x20000000;
+const ROTATED_HEXAGONAL: u32 = 0x10000000;
+const TILE_FLAGS: u32 =
+    FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY | ROTATED_HEXAGONAL;
+
+/// A tileset, with the pixels of every tile
+struct Tileset {
+    name: String,
+    first_gid: u32,
+    /// The size of each tile in pixels
+    tile_size: Vector2<usize>,
+    tiles: Vec<Vec<Color>>,
+    /// The custom properties of each tile, by tile ID
+    properties: HashMap<u32, HashMap<String, String>>,
+    transparent_color: Option<Color>,
+}
+
+struct TileLayer {
+    name: String,
+    /// The global tile ID (with the flip flags) of every cell of the layer
+    cells: Vec<u32>,
+}
+
+struct TiledMap {
+    /// The size of the map in tiles
+    size: Vector2<usize>,
+    /// The size of each tile in pixels
+    tile_size: Vector2<usize>,
+    tilesets: Vec<Tileset>,
+    layers: Vec<TileLayer>,
+}
+
+/// Make tiles from the tilesets of a Tiled map, and a map from each of its tile layers (written as "map_<layer>" sections).
+/// Tile 0 is an empty tile for the empty cells of the layers. The "palette" and "priority" properties of a tile are used
+/// for the map entries that use it. Returns None if the file isn't a .tmx or .tmj file
+pub fn read_tiled_map(file: &str, settings: &TileSettings) -> Option<FinishedRawData> {
+    let map = match get_extension(file).as_deref() {
+        Some("tmx") => read_tmx(file),
+        Some("tmj") => read_tmj(file),
+        _ => return None,
+    };
+
+    Some(convert_map(&map, settings))
+}
+
+/// Whether the file is a Tiled map, going by its extension
+pub fn is_tiled_map(file: &str) -> bool {
+    matches!(get_extension(file).as_deref(), Some("tmx" | "tmj"))
+}
+
+/// The extension of a file, in lowercase
+fn get_extension(file: &str) -> Option<String> {
+    Path::new(file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// Make sure the settings can be used for a Tiled map, which is made of whole tiles instead of one image
+fn validate_map_settings(map: &TiledMap, settings: &TileSettings) {
+    if settings.bpp == BitsPerPixel::Bpp16 {
+        panic!("Error: Tiled maps need a paletted bit-depth!");
+    }
+    if settings.map_format == MapFormat::Mode7 {
+        panic!("Error: Tiled maps can't be made into Mode 7 maps!");
+    }
+    // The NES sub-palettes are chosen for each 16x16 area of the screen, which the tiles of a tileset don't have
+    if settings.tile_format == TileFormat::Nes {
+        panic!("Error: Tiled maps can't be made into NES tiles!");
+    }
+
+    let unsupported = [
+        (settings.crop.is_some(), "--crop"),
+        (settings.pad_to_tile, "--pad-to-tile"),
+        (settings.preserve_palette, "--preserve-palette"),
+        (settings.frame_size.is_some(), "--frame-size"),
+        (settings.texture_format.is_some(), "--texture-format"),
+        (settings.sprite_mapping.is_some(), "--sprite-mapping"),
+        (settings.layout == Layout::Bitmap, "--layout bitmap"),
+    ];
+
+    for (used, option) in unsupported {
+        if used {
+            panic!("Error: {} can't be used with Tiled maps!", option);
+        }
+    }
+
+    if settings.attribute_image.is_some() {
+        panic!("Error: --attribute-image can't be used with Tiled maps, please use --attribute-layer instead!");
+    }
+
+    for tileset in &map.tilesets {
+        if tileset.tile_size != map.tile_size {
+            panic!(
+                "Error: the tileset {} has {}x{} tiles, but the map has {}x{} tiles!",
+                tileset.name,
+                tileset.tile_size.x,
+                tileset.tile_size.y,
+                map.tile_size.x,
+                map.tile_size.y
+            );
+        }
+    }
+
+    for layer in &map.layers {
+        if layer.cells.len() != map.size.x * map.size.y {
+            panic!(
+                "Error: layer {} has {} tiles, but the map is {}x{} tiles!",
+                layer.name,
+                layer.cells.len(),
+                map.size.x,
+                map.size.y
+            );
+        }
+    }
+}
+
+/// Convert the tiles and layers of a map with the settings
+fn convert_map(map: &TiledMap, settings: &TileSettings) -> FinishedRawData {
+    validate_map_settings(map, settings);
+
+    let tile_size = Vector2 {
+        x: settings.size_per_tile.x as usize,
+        y: settings.size_per_tile.y as usize,
+    };
+
+    if !map.tile_size.x.is_multiple_of(tile_size.x) || !map.tile_size.y.is_multiple_of(tile_size.y)
+    {
+        panic!(
+            "Error: the {}x{} tiles of the map can't be split into {}x{} tiles!",
+            map.tile_size.x, map.tile_size.y, tile_size.x, tile_size.y
+        );
+    }
+
+    // How many of our tiles each tile of the map is made of
+    let subtiles = Vector2 {
+        x: map.tile_size.x / tile_size.x,
+        y: map.tile_size.y / tile_size.y,
+    };
+    let empty = Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    };
+
+    // The empty tile comes first, then every tile of every tileset
+    let mut tileset_starts: Vec<usize> = Vec::new();
+    let mut pixels: Vec<Color> = vec![empty; tile_size.x * tile_size.y];
+
+    for tileset in &map.tilesets {
+        tileset_starts.push(pixels.len() / (tile_size.x * tile_size.y));
+
+        for tile in &tileset.tiles {
+            for subtile in image_processor::tile(tile, map.tile_size, tile_size) {
+                pixels.extend(subtile);
+            }
+        }
+    }
+
+    let settings = TileSettings {
+        image_size: Vector2 {
+            x: tile_size.x,
+            y: pixels.len() / tile_size.x,
+        },
+        transparent_color: settings.transparent_color.or(map
+            .tilesets
+            .iter()
+            .find_map(|tileset| tileset.transparent_color)),
+        ..settings.clone()
+    };
+
+    let mut final_data = FinishedRawData::default();
+
+    // All the tilesets share one palette
+    let (pixels, settings) = image_processor::apply_transparency(pixels, settings);
+    let mut palette = image_processor::get_image_palette(&pixels, &settings);
+    let indices = image_processor::convert_to_paletted(&pixels, &settings, &palette);
+    image_processor::pad_palette(&mut palette, &settings);
+
+    // Merge the tiles that are the same, and remember which unique tile (and flip) each tile became
+    let tiles: Vec<Vec<u32>> = indices
+        .chunks(tile_size.x * tile_size.y)
+        .map(|tile| tile.to_vec())
+        .collect();
+    let (unique_tiles, tile_entries) = map_processor::create_map(&tiles, &settings);
+
+    for tile in unique_tiles {
+        final_data
+            .image_data
+            .append(&mut image_processor::process_tile_paletted(
+                &tile, &settings,
+            ));
+    }
+
+    // The attribute layer becomes the attribute map instead of a map
+    let (attribute_layers, layers): (Vec<&TileLayer>, Vec<&TileLayer>) = map
+        .layers
+        .iter()
+        .partition(|layer| settings.attribute_layer.as_ref() == Some(&layer.name));
+
+    if let Some(ref name) = settings.attribute_layer {
+        let Some(layer) = attribute_layers.first() else {
+            panic!("Error: the map has no layer called {}!", name);
+        };
+        let size = Vector2 {
+            x: map.size.x * map.tile_size.x,
+            y: map.size.y * map.tile_size.y,
+        };
+
+        final_data.sections.push(Section {
+            name: "tileattr".to_string(),
+            data: attribute_processor::create_attribute_map(
+                &render_layer(map, layer),
+                size,
+                &settings,
+            ),
+        });
+    }
+
+    for layer in layers {
+        let mut entries: Vec<MapEntry> = Vec::new();
+
+        for y in 0..map.size.y * subtiles.y {
+            for x in 0..map.size.x * subtiles.x {
+                let cell = layer.cells[(y / subtiles.y) * map.size.x + x / subtiles.x];
+                let gid = cell & !TILE_FLAGS;
+
+                // Empty cells use the empty tile
+                if gid == 0 {
+                    entries.push(MapEntry {
+                        palette: settings.map_palette,
+                        priority: settings.map_priority,
+                        ..tile_entries[0]
+                    });
+                    continue;
+                }
+
+                if cell & (FLIPPED_DIAGONALLY | ROTATED_HEXAGONAL) != 0 {
+                    panic!(
+                        "Error: layer {} has rotated tiles, which can't be made with flipping!",
+                        layer.name
+                    );
+                }
+
+                let hflip = cell & FLIPPED_HORIZONTALLY != 0;
+                let vflip = cell & FLIPPED_VERTICALLY != 0;
+
+                let tileset = map
+                    .tilesets
+                    .iter()
+                    .rposition(|tileset| tileset.first_gid <= gid)
+                    .filter(|&tileset| {
+                        ((gid - map.tilesets[tileset].first_gid) as usize)
+                            < map.tilesets[tileset].tiles.len()
+                    })
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Error: layer {} uses tile {}, which isn't in any tileset!",
+                            layer.name, gid
+                        )
+                    });
+                let id = gid - map.tilesets[tileset].first_gid;
+
+                // Flipping a tile made of several of our tiles also swaps where they are
+                let subtile = Vector2 {
+                    x: if hflip {
+                        subtiles.x - 1 - x % subtiles.x
+                    } else {
+                        x % subtiles.x
+                    },
+                    y: if vflip {
+                        subtiles.y - 1 - y % subtiles.y
+                    } else {
+                        y % subtiles.y
+                    },
+                };
+                let tile_entry =
+                    tile_entries[(tileset_starts[tileset] + id as usize) * subtiles.x * subtiles.y
+                        + subtile.y * subtiles.x
+                        + subtile.x];
+
+                // The flip of the cell is added to the flip the unique tile needs to look like this tile
+                let hflip = hflip != tile_entry.hflip;
+                let vflip = vflip != tile_entry.vflip;
+
+                if (hflip || vflip) && !settings.map_format.supports_flipping() {
+                    panic!(
+                        "Error: layer {} has flipped tiles, but the selected map format can't flip tiles!",
+                        layer.name
+                    );
+                }
+
+                let properties = map.tilesets[tileset].properties.get(&id);
+                let property = |name: &str| properties.and_then(|properties| properties.get(name));
+
+                entries.push(MapEntry {
+                    tile: tile_entry.tile,
+                    hflip,
+                    vflip,
+                    palette: property("palette")
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(settings.map_palette),
+                    priority: property("priority")
+                        .map(|value| value == "true")
+                        .unwrap_or(settings.map_priority),
+                });
+            }
+        }
+
+        // Every layer gets its own map (and its own copies of any extra sections of the map format), named so the layer
+        // name can be part of a file name
+        let mut layer_data = FinishedRawData::default();
+        map_processor::encode_map(&entries, &settings, &mut layer_data);
+        let name = output_processor::get_file_safe_name(&layer.name);
+
+        final_data.sections.push(Section {
+            name: format!("map_{}", name),
+            data: layer_data.map_data,
+        });
+
+        for section in layer_data.sections {
+            final_data.sections.push(Section {
+                name: format!("{}_{}", section.name, name),
+                data: section.data,
+            });
+        }
+    }
+
+    final_data.palette_data =
+        color_processor::encode_palette(&palette, &settings.get_palette_encoder());
+
+    final_data.palette = palette;
+
+    final_data
+}
+
+/// Draw a layer with the tiles of its tilesets
+fn render_layer(map: &TiledMap, layer: &TileLayer) -> Vec<Color> {
+    let width = map.size.x * map.tile_size.x;
+    let mut pixels = vec![
+        Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        };
+        width * map.size.y * map.tile_size.y
+    ];
+
+    for (i, &cell) in layer.cells.iter().enumerate() {
+        let gid = cell & !TILE_FLAGS;
+
+        let Some(tileset) = map
+            .tilesets
+            .iter()
+            .rfind(|tileset| tileset.first_gid <= gid)
+        else {
+            continue;
+        };
+        let Some(tile) = tileset.tiles.get((gid - tileset.first_gid) as usize) else {
+            continue;
+        };
+
+        let tile = map_processor::flip_tile(
+            tile,
+            map.tile_size,
+            cell & FLIPPED_HORIZONTALLY != 0,
+            cell & FLIPPED_VERTICALLY != 0,
+        );
+        let x = (i % map.size.x) * map.tile_size.x;
+        let y = (i / map.size.x) * map.tile_size.y;
+
+        for (row, colors) in tile.chunks(map.tile_size.x).enumerate() {
+            let start = (y + row) * width + x;
+
+            pixels[start..start + map.tile_size.x].copy_from_slice(colors);
+        }
+    }
+
+    pixels
+}
+
+/// Read a map saved in Tiled's XML format
+fn read_tmx(file: &str) -> TiledMap {
+    let text = read_text_file(file);
+    let document = roxmltree::Document::parse(&text)
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+    let root = document.root_element();
+    let directory = Path::new(file).parent().unwrap_or(Path::new(""));
+
+    if root.attribute("infinite") == Some("1") {
+        panic!("Error: infinite Tiled maps aren't supported!");
+    }
+
+    let number = |name: &str| -> usize {
+        root.attribute(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("Error: the map in {} has no {}!", file, name))
+    };
+
+    let tilesets = root
+        .children()
+        .filter(|node| node.has_tag_name("tileset"))
+        .map(|node| {
+            let first_gid = node
+                .attribute("firstgid")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1);
+
+            match node.attribute("source") {
+                Some(source) => read_external_tileset(&directory.join(source), first_gid),
+                None => read_tsx_tileset(node, directory, first_gid),
+            }
+        })
+        .collect();
+
+    let mut layers: Vec<TileLayer> = Vec::new();
+    read_tmx_layers(root, &mut layers);
+
+    TiledMap {
+        size: Vector2::new(number("width"), number("height")),
+        tile_size: Vector2::new(number("tilewidth"), number("tileheight")),
+        tilesets,
+        layers,
+    }
+}
+
+/// Read the tile layers of a map or group, including the layers inside groups
+fn read_tmx_layers(node: roxmltree::Node, layers: &mut Vec<TileLayer>) {
+    for child in node.children() {
+        if child.has_tag_name("group") {
+            read_tmx_layers(child, layers);
+        } else if child.has_tag_name("layer") {
+            let name = child.attribute("name").unwrap_or("").to_string();
+            let Some(data) = child.children().find(|node| node.has_tag_name("data")) else {
+                continue;
+            };
+
+            let cells = match data.attribute("encoding") {
+                Some(encoding) => decode_layer_data(
+                    data.text().unwrap_or(""),
+                    encoding,
+                    data.attribute("compression"),
+                ),
+                // Without an encoding, every tile is its own element
+                None => data
+                    .children()
+                    .filter(|node| node.has_tag_name("tile"))
+                    .map(|node| {
+                        node.attribute("gid")
+                            .and_then(|gid| gid.parse().ok())
+                            .unwrap_or(0)
+                    })
+                    .collect(),
+            };
+
+            layers.push(TileLayer { name, cells });
+        }
+    }
+}
+
+/// Read a tileset element of a .tmx or .tsx file
+fn read_tsx_tileset(node: roxmltree::Node, directory: &Path, first_gid: u32) -> Tileset {
+    let number =
+        |name: &str| -> Option<usize> { node.attribute(name).and_then(|value| value.parse().ok()) };
+
+    let Some(image) = node.children().find(|node| node.has_tag_name("image")) else {
+        panic!("Error: tilesets made of separate images aren't supported!");
+    };
+
+    let mut properties: HashMap<u32, HashMap<String, String>> = HashMap::new();
+
+    for tile in node.children().filter(|node| node.has_tag_name("tile")) {
+        let id: u32 = tile
+            .attribute("id")
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(0);
+
+        let tile_properties = tile
+            .descendants()
+            .filter(|node| node.has_tag_name("property"))
+            .map(|property| {
+                (
+                    property.attribute("name").unwrap_or("").to_string(),
+                    property.attribute("value").unwrap_or("").to_string(),
+                )
+            })
+            .collect();
+
+        properties.insert(id, tile_properties);
+    }
+
+    let tile_size = Vector2::new(
+        number("tilewidth").unwrap_or(0),
+        number("tileheight").unwrap_or(0),
+    );
+
+    Tileset {
+        name: node.attribute("name").unwrap_or("").to_string(),
+        first_gid,
+        tile_size,
+        tiles: read_tileset_image(
+            &directory.join(image.attribute("source").unwrap_or("")),
+            tile_size,
+            number("margin").unwrap_or(0),
+            number("spacing").unwrap_or(0),
+            number("tilecount"),
+        ),
+        properties,
+        transparent_color: image.attribute("trans").map(parse_color),
+    }
+}
+
+/// Read a map saved in Tiled's JSON format
+fn read_tmj(file: &str) -> TiledMap {
+    let root = read_json_file(file);
+    let directory = Path::new(file).parent().unwrap_or(Path::new(""));
+
+    if root["infinite"].as_bool() == Some(true) {
+        panic!("Error: infinite Tiled maps aren't supported!");
+    }
+
+    let number = |name: &str| -> usize {
+        root[name]
+            .as_u64()
+            .unwrap_or_else(|| panic!("Error: the map in {} has no {}!", file, name))
+            as usize
+    };
+
+    let tilesets = root["tilesets"]
+        .as_array()
+        .map(|tilesets| tilesets.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|tileset| {
+            let first_gid = tileset["firstgid"].as_u64().unwrap_or(1) as u32;
+
+            match tileset["source"].as_str() {
+                Some(source) => read_external_tileset(&directory.join(source), first_gid),
+                None => read_tsj_tileset(tileset, directory, first_gid),
+            }
+        })
+        .collect();
+
+    let mut layers: Vec<TileLayer> = Vec::new();
+    read_tmj_layers(&root["layers"], &mut layers);
+
+    TiledMap {
+        size: Vector2::new(number("width"), number("height")),
+        tile_size: Vector2::new(number("tilewidth"), number("tileheight")),
+        tilesets,
+        layers,
+    }
+}
+
+/// Read the tile layers of a map or group, including the layers inside groups
+fn read_tmj_layers(value: &Value, layers: &mut Vec<TileLayer>) {
+    for layer in value
+        .as_array()
+        .map(|layers| layers.as_slice())
+        .unwrap_or_default()
+    {
+        match layer["type"].as_str() {
+            Some("group") => read_tmj_layers(&layer["layers"], layers),
+            Some("tilelayer") => {
+                let cells = match &layer["data"] {
+                    Value::String(data) => decode_layer_data(
+                        data,
+                        layer["encoding"].as_str().unwrap_or("base64"),
+                        layer["compression"]
+                            .as_str()
+                            .filter(|compression| !compression.is_empty()),
+                    ),
+                    data => data
+                        .as_array()
+                        .map(|data| data.as_slice())
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|gid| gid.as_u64().unwrap_or(0) as u32)
+                        .collect(),
+                };
+
+                layers.push(TileLayer {
+                    name: layer["name"].as_str().unwrap_or("").to_string(),
+                    cells,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Read a tileset object of a .tmj or .tsj file
+fn read_tsj_tileset(tileset: &Value, directory: &Path, first_gid: u32) -> Tileset {
+    let number =
+        |name: &str| -> Option<usize> { tileset[name].as_u64().map(|value| value as usize) };
+
+    let Some(image) = tileset["image"].as_str() else {
+        panic!("Error: tilesets made of separate images aren't supported!");
+    };
+
+    let mut properties: HashMap<u32, HashMap<String, String>> = HashMap::new();
+
+    for tile in tileset["tiles"]
+        .as_array()
+        .map(|tiles| tiles.as_slice())
+        .unwrap_or_default()
+    {
+        let id = tile["id"].as_u64().unwrap_or(0) as u32;
+
+        let tile_properties = tile["properties"]
+            .as_array()
+            .map(|properties| properties.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|property| {
+                let value = match &property["value"] {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+
+                (property["name"].as_str().unwrap_or("").to_string(), value)
+            })
+            .collect();
+
+        properties.insert(id, tile_properties);
+    }
+
+    let tile_size = Vector2::new(
+        number("tilewidth").unwrap_or(0),
+        number("tileheight").unwrap_or(0),
+    );
+
+    Tileset {
+        name: tileset["name"].as_str().unwrap_or("").to_string(),
+        first_gid,
+        tile_size,
+        tiles: read_tileset_image(
+            &directory.join(image),
+            tile_size,
+            number("margin").unwrap_or(0),
+            number("spacing").unwrap_or(0),
+            number("tilecount"),
+        ),
+        properties,
+        transparent_color: tileset["transparentcolor"].as_str().map(parse_color),
+    }
+}
+
+/// Read a tileset from its own .tsx or .tsj file
+fn read_external_tileset(path: &Path, first_gid: u32) -> Tileset {
+    let file = path.to_string_lossy();
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    match path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        Some("tsx") => {
+            let text = read_text_file(&file);
+            let document = roxmltree::Document::parse(&text)
+                .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error));
+
+            read_tsx_tileset(document.root_element(), directory, first_gid)
+        }
+        _ => read_tsj_tileset(&read_json_file(&file), directory, first_gid),
+    }
+}
+
+/// Cut the tiles out of the image of a tileset
+fn read_tileset_image(
+    path: &Path,
+    tile_size: Vector2<usize>,
+    margin: usize,
+    spacing: usize,
+    tile_count: Option<usize>,
+) -> Vec<Vec<Color>> {
+    let image = image::open(path)
+        .unwrap_or_else(|error| panic!("Unable to open file {}! Reason: {}", path.display(), error))
+        .into_rgba8();
+    let size = Vector2::new(image.width() as usize, image.height() as usize);
+
+    if tile_size.x == 0 || tile_size.y == 0 {
+        panic!("Error: the tileset {} has no tile size!", path.display());
+    }
+
+    let columns = (size.x.saturating_sub(margin * 2) + spacing) / (tile_size.x + spacing);
+    let rows = (size.y.saturating_sub(margin * 2) + spacing) / (tile_size.y + spacing);
+
+    (0..tile_count.unwrap_or(columns * rows).min(columns * rows))
+        .map(|id| {
+            let x = margin + (id % columns) * (tile_size.x + spacing);
+            let y = margin + (id / columns) * (tile_size.y + spacing);
+
+            (y..y + tile_size.y)
+                .flat_map(|y| (x..x + tile_size.x).map(move |x| (x, y)))
+                .map(|(x, y)| Color::from(image.get_pixel(x as u32, y as u32).0))
+                .collect()
+        })
+        .collect()
+}
+
+/// Decode the tiles of a layer stored as CSV or base64 (optionally compressed with zlib or gzip)
+fn decode_layer_data(text: &str, encoding: &str, compression: Option<&str>) -> Vec<u32> {
+    match encoding {
+        "csv" => text
+            .split(',')
+            .map(|gid| gid.trim().parse().unwrap_or(0))
+            .collect(),
+        "base64" => {
+            let compressed = base64::engine::general_purpose::STANDARD
+                .decode(text.trim())
+                .unwrap_or_else(|error| {
+                    panic!("Error: a layer has broken base64 data! Reason: {}", error)
+                });
+
+            let mut bytes: Vec<u8> = Vec::new();
+
+            let result = match compression {
+                None => {
+                    bytes = compressed;
+                    Ok(0)
+                }
+                Some("zlib") => ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut bytes),
+                Some("gzip") => GzDecoder::new(compressed.as_slice()).read_to_end(&mut bytes),
+                Some(compression) => panic!(
+                    "Error: layers compressed with {} aren't supported!",
+                    compression
+                ),
+            };
+
+            if let Err(error) = result {
+                panic!(
+                    "Error: a layer has broken compressed data! Reason: {}",
+                    error
+                );
+            }
+
+            bytes
+                .chunks_exact(4)
+                .map(|gid| u32::from_le_bytes(gid.try_into().unwrap()))
+                .collect()
+        }
+        _ => panic!("Error: layers encoded as {} aren't supported!", encoding),
+    }
+}
+
+/// Parse a color written as (#)rrggbb
+fn parse_color(text: &str) -> Color {
+    let value = u32::from_str_radix(text.trim_start_matches('#'), 16).unwrap_or(0);
+    let [_, r, g, b] = value.to_be_bytes();
+
+    Color { r, g, b, a: 255 }
+}
+
+fn read_text_file(file: &str) -> String {
+    fs::read_to_string(file)
+        .unwrap_or_else(|error| panic!("Unable to open file {}! Reason: {}", file, error))
+}
+
+fn read_json_file(file: &str) -> Value {
+    serde_json::from_str(&read_text_file(file))
+        .unwrap_or_else(|error| panic!("Unable to read file {}! Reason: {}", file, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+
+    use super::*;
+
+    fn solid(value: u8) -> Vec<Color> {
+        vec![
+            Color {
+                r: value,
+                g: value,
+                b: value,
+                a: 255
+            };
+            64
+        ]
+    }
+
+    /// A 2x2 map of 8x8 tiles with one layer, using a tileset with a tile of each of the given shades
+    fn build_map(shades: &[u8], cells: Vec<u32>) -> TiledMap {
+        TiledMap {
+            size: Vector2::new(2, 2),
+            tile_size: Vector2::new(8, 8),
+            tilesets: vec![Tileset {
+                name: "tiles".to_string(),
+                first_gid: 1,
+                tile_size: Vector2::new(8, 8),
+                tiles: shades.iter().map(|&shade| solid(shade)).collect(),
+                properties: HashMap::new(),
+                transparent_color: None,
+            }],
+            layers: vec![TileLayer {
+                name: "ground".to_string(),
+                cells,
+            }],
+        }
+    }
+
+    fn settings() -> TileSettings {
+        TileSettings {
+            bpp: BitsPerPixel::Bpp4,
+            transparent_color: None,
+            ..Default::default()
+        }
+    }
+
+    fn map_entries(data: &FinishedRawData) -> Vec<u16> {
+        data.sections
+            .iter()
+            .find(|section| section.name == "map_ground")
+            .unwrap()
+            .data
+            .chunks(2)
+            .map(|entry| u16::from_le_bytes([entry[0], entry[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_layer_data() {
+        let gids: Vec<u32> = vec![1, 2, 0x8000_0003, 0];
+        let bytes: Vec<u8> = gids.iter().flat_map(|gid| gid.to_le_bytes()).collect();
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&bytes).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&bytes).unwrap();
+
+        assert_eq!(decode_layer_data("1,2,\n2147483651,0", "csv", None), gids);
+        assert_eq!(decode_layer_data(&encode(&bytes), "base64", None), gids);
+        assert_eq!(
+            decode_layer_data(&encode(&zlib.finish().unwrap()), "base64", Some("zlib")),
+            gids
+        );
+        assert_eq!(
+            decode_layer_data(&encode(&gzip.finish().unwrap()), "base64", Some("gzip")),
+            gids
+        );
+    }
+
+    #[test]
+    fn keeps_empty_cells_apart_from_tiles() {
+        let map = build_map(&[50, 100, 150], vec![1, 2, 0x8000_0003, 0]);
+        let data = convert_map(&map, &settings());
+
+        // Tile 0 is the empty tile, the tiles of the tileset come after it
+        assert_eq!(map_entries(&data), vec![0x0001, 0x0002, 0x0403, 0x0000]);
+        assert_eq!(data.image_data.len(), 4 * 32);
+        assert!(data.image_data[..32].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn merges_tiles_with_map_reduce() {
+        // The second tile of the tileset is a mirrored copy of the first one
+        let mut tile: Vec<Color> = solid(50);
+        for row in tile.chunks_mut(8) {
+            row[0] = solid(100)[0];
+        }
+        let mirrored = map_processor::flip_tile(&tile, Vector2::new(8, 8), true, false);
+
+        let mut map = build_map(&[], vec![1, 2, 0x8000_0002, 0]);
+        map.tilesets[0].tiles = vec![tile, mirrored];
+
+        let data = convert_map(
+            &map,
+            &TileSettings {
+                map_reduce: MapReduce::Flipped,
+                ..settings()
+            },
+        );
+
+        // Flipping the mirrored tile again cancels out
+        assert_eq!(map_entries(&data), vec![0x0001, 0x0401, 0x0001, 0x0000]);
+        assert_eq!(data.image_data.len(), 2 * 32);
+    }
+
+    #[test]
+    fn names_maps_after_file_safe_layer_names() {
+        let mut map = build_map(&[50], vec![1, 1, 1, 1]);
+        map.layers[0].name = "fg/top".to_string();
+
+        let data = convert_map(&map, &settings());
+
+        assert_eq!(data.sections[0].name, "map_fg_top");
+    }
+
+    #[test]
+    #[should_panic(expected = "layer ground has 3 tiles, but the map is 2x2 tiles")]
+    fn rejects_short_layers() {
+        convert_map(&build_map(&[50], vec![1, 1, 1]), &settings());
+    }
+
+    #[test]
+    #[should_panic(expected = "the tileset tiles has 16x16 tiles, but the map has 8x8 tiles")]
+    fn rejects_tilesets_with_other_tile_sizes() {
+        let mut map = build_map(&[50], vec![1, 1, 1, 1]);
+        map.tilesets[0].tile_size = Vector2::new(16, 16);
+
+        convert_map(&map, &settings());
+    }
+
+    #[test]
+    #[should_panic(expected = "Tiled maps can't be made into NES tiles")]
+    fn rejects_nes_tiles() {
+        convert_map(
+            &build_map(&[50], vec![1, 1, 1, 1]),
+            &TileSettings {
+                bpp: BitsPerPixel::Bpp2,
+                tile_format: TileFormat::Nes,
+                ..settings()
+            },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "--crop can't be used with Tiled maps")]
+    fn rejects_cropping() {
+        convert_map(
+            &build_map(&[50], vec![1, 1, 1, 1]),
+            &TileSettings {
+                crop: Some((Vector2::new(0, 0), Vector2::new(8, 8))),
+                ..settings()
+            },
+        );
+    }
+}